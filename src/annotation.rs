@@ -0,0 +1,360 @@
+use clap::ValueEnum;
+use itertools::Itertools;
+
+use crate::*;
+
+/// Attributes that are tried in order to name a feature of a GFF3/GTF annotation, unless `--name-attribute` is given
+const NAME_ATTRIBUTES: [&str; 5] = ["ID", "Name", "gene_id", "transcript_id", "Parent"];
+
+/// Layout of the annotation file
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    /// Detect the format from the file extension or the first line of the file
+    #[default]
+    Auto,
+    /// Six tab-separated columns: chromosome, start, end, name, score, strand, optionally followed by the six BED12 columns describing the exons
+    Bed,
+    /// GFF3 with key=value attributes
+    Gff,
+    /// GTF with key "value" attributes
+    Gtf,
+}
+
+impl AnnotationFormat {
+    /// Detect the format of an annotation file from its extension, falling back to the first line that is not a comment.
+    pub fn detect(path: &str, line: &str) -> Option<Self> {
+        let extension = path.rsplit('.').next().unwrap_or_default();
+        match extension {
+            "bed" => return Some(AnnotationFormat::Bed),
+            "gff" | "gff3" => return Some(AnnotationFormat::Gff),
+            "gtf" => return Some(AnnotationFormat::Gtf),
+            _ => {}
+        }
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.len() {
+            6 | 12 => Some(AnnotationFormat::Bed),
+            9 if fields[8].contains('=') => Some(AnnotationFormat::Gff),
+            9 => Some(AnnotationFormat::Gtf),
+            _ => None,
+        }
+    }
+
+    /// Split the attribute column of a GFF3 (`ID=AT1G01010;Name=NAC001`) or GTF (`gene_id "AT1G01010"; gene_name "NAC001";`) line into key-value pairs
+    pub fn attributes<'a>(&self, column: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        let format = *self;
+        column
+            .split(';')
+            .map(str::trim)
+            .filter(|attribute| !attribute.is_empty())
+            .filter_map(move |attribute| match format {
+                AnnotationFormat::Gtf => attribute
+                    .split_once(' ')
+                    .map(|(key, value)| (key, value.trim().trim_matches('"'))),
+                _ => attribute.split_once('='),
+            })
+    }
+}
+
+impl Gene {
+    /// Create a new gene from a feature line of a GFF3 or GTF annotation.
+    /// Only yields a gene if the feature is of one of the requested types and carries a name attribute.
+    ///
+    /// GFF3 and GTF coordinates are 1-based and inclusive on both ends. The start is converted to the 0-based start of BED files, so that a gene is placed the same way whichever format it is annotated in.
+    /// The contig of the gene is added to the dictionary if it is not known yet.
+    pub fn from_gff_file_line(
+        s: &str,
+        contigs: &mut Contigs,
+        format: AnnotationFormat,
+        feature_types: &[String],
+        name_attribute: Option<&str>,
+        invert_strand: bool,
+    ) -> Option<Self> {
+        let (chromosome, _, feature_type, start, end, _, strand, _, attributes) =
+            s.split('\t').collect_tuple()?;
+        if !feature_types.iter().any(|t| t == feature_type) {
+            return None;
+        }
+        let attributes: Vec<(&str, &str)> = format.attributes(attributes).collect();
+        let find = |key: &str| attributes.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+        let name = match name_attribute {
+            Some(key) => find(key),
+            None => NAME_ATTRIBUTES.iter().find_map(|key| find(key)),
+        }?;
+
+        let start = start.parse::<i32>().ok()? - 1;
+        let end = end.parse::<i32>().ok()?;
+        Some(Gene {
+            chromosome: contigs.intern(chromosome),
+            start,
+            end,
+            name: String::from(name),
+            strand: Strand::from_symbol(strand, invert_strand),
+            flanks: None,
+            transcript: None,
+        })
+    }
+}
+
+/// Read all genes from the annotation file. Comment lines, the `track` and `browser` lines of UCSC BED files and lines that do not describe a requested feature are skipped.
+pub fn load_genes(args: &Args, contigs: &mut Contigs) -> Result<Vec<Gene>> {
+    let mut format = args.annotation_format;
+    let mut genes = Vec::new();
+    let mut parts = TranscriptParts::default();
+
+    for line in lines_from_file(&args.genome)? {
+        let line = line?;
+        if format == AnnotationFormat::Auto && line.starts_with("##gff-version") {
+            format = AnnotationFormat::Gff;
+        }
+        // UCSC BED files may start with track and browser lines
+        let is_ucsc_header = matches!(line.split_whitespace().next(), Some("track" | "browser"));
+        if line.starts_with('#') || line.trim().is_empty() || is_ucsc_header {
+            continue;
+        }
+        if format == AnnotationFormat::Auto {
+            format = AnnotationFormat::detect(&args.genome, &line)
+                .ok_or_else(|| Error::UnknownAnnotationFormat(args.genome.clone()))?;
+        }
+
+        let gene = match format {
+            AnnotationFormat::Bed | AnnotationFormat::Auto => {
+                Gene::from_annotation_file_line(&line, contigs, args.invert)
+            }
+            AnnotationFormat::Gff | AnnotationFormat::Gtf => Gene::from_gff_file_line(
+                &line,
+                contigs,
+                format,
+                &args.feature_types,
+                args.name_attribute.as_deref(),
+                args.invert,
+            ),
+        };
+        if let Some(gene) = gene {
+            genes.push(gene)
+        }
+        if args.transcripts && matches!(format, AnnotationFormat::Gff | AnnotationFormat::Gtf) {
+            parts.read_line(&line, format, args.name_attribute.as_deref());
+        }
+    }
+    if args.transcripts {
+        parts.attach(&mut genes);
+    }
+
+    if genes.is_empty() {
+        return Err(Error::NoGenes(args.genome.clone()));
+    }
+    Ok(genes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AnnotationFormat;
+    use crate::*;
+
+    const GFF: &str = "1\tAraport11\tgene\t3631\t5899\t.\t+\t.\tID=AT1G01010;Name=NAC001";
+    const GFF_MRNA: &str =
+        "1\tAraport11\tmRNA\t3631\t5899\t.\t+\t.\tID=AT1G01010.1;Parent=AT1G01010";
+    const GTF: &str =
+        "1\tAraport11\tgene\t6788\t9130\t.\t-\t.\tgene_id \"AT1G01020\"; gene_name \"ARV1\";";
+
+    fn types(types: &[&str]) -> Vec<String> {
+        types.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn detect_annotation_format() {
+        assert_eq!(
+            AnnotationFormat::detect("genes.gff3", ""),
+            Some(AnnotationFormat::Gff)
+        );
+        assert_eq!(
+            AnnotationFormat::detect("genes.txt", GFF),
+            Some(AnnotationFormat::Gff)
+        );
+        assert_eq!(
+            AnnotationFormat::detect("genes.txt", GTF),
+            Some(AnnotationFormat::Gtf)
+        );
+        assert_eq!(
+            AnnotationFormat::detect("genes.txt", "1\t100\t200\tAT1G01010\t0\t+"),
+            Some(AnnotationFormat::Bed)
+        );
+        assert_eq!(
+            AnnotationFormat::detect(
+                "genes.txt",
+                "1\t100\t200\tAT1G01010\t0\t+\t110\t180\t0\t2\t30,60\t0,40"
+            ),
+            Some(AnnotationFormat::Bed)
+        );
+    }
+
+    #[test]
+    fn gene_from_bed12_line() {
+        let gene = Gene::from_annotation_file_line(
+            "1\t100\t200\tAT1G01010\t0\t-\t110\t180\t0\t2\t30,60\t0,40",
+            &mut Contigs::new(),
+            false,
+        )
+        .unwrap();
+        assert_eq!(gene.end, 200);
+        let transcript = gene.transcript.unwrap();
+        assert_eq!(transcript.exons, vec![(101, 130), (141, 200)]);
+        assert_eq!(transcript.cds, Some((111, 180)));
+        assert_eq!(
+            transcript.classify(120, &Strand::Antisense),
+            FeatureClass::Cds
+        );
+    }
+
+    #[test]
+    fn gene_from_gff_line() {
+        let gene = Gene::from_gff_file_line(
+            GFF,
+            &mut Contigs::new(),
+            AnnotationFormat::Gff,
+            &types(&["gene"]),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(gene.name, "AT1G01010");
+        assert_eq!(gene.start, 3630);
+        assert_eq!(gene.end, 5899);
+        assert_eq!(gene.strand, Strand::Sense);
+
+        let named = Gene::from_gff_file_line(
+            GFF,
+            &mut Contigs::new(),
+            AnnotationFormat::Gff,
+            &types(&["gene"]),
+            Some("Name"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(named.name, "NAC001");
+    }
+
+    #[test]
+    fn gene_from_gff_line_filters_feature_type() {
+        let genes = types(&["gene"]);
+        assert!(Gene::from_gff_file_line(
+            GFF_MRNA,
+            &mut Contigs::new(),
+            AnnotationFormat::Gff,
+            &genes,
+            None,
+            false
+        )
+        .is_none());
+        let transcripts = types(&["gene", "mRNA"]);
+        let mrna = Gene::from_gff_file_line(
+            GFF_MRNA,
+            &mut Contigs::new(),
+            AnnotationFormat::Gff,
+            &transcripts,
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(mrna.name, "AT1G01010.1");
+    }
+
+    #[test]
+    fn gene_from_gtf_line() {
+        let gene = Gene::from_gff_file_line(
+            GTF,
+            &mut Contigs::new(),
+            AnnotationFormat::Gtf,
+            &types(&["gene"]),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(gene.name, "AT1G01020");
+        assert_eq!(gene.start, 6787);
+        assert_eq!(gene.end, 9130);
+        assert_eq!(gene.strand, Strand::Antisense);
+    }
+
+    #[test]
+    fn gff_gene_placed_like_bed_gene() {
+        let mut contigs = Contigs::new();
+        let bed =
+            Gene::from_annotation_file_line("1\t3630\t5899\tAT1G01010\t0\t+", &mut contigs, false)
+                .unwrap();
+        let gff = Gene::from_gff_file_line(
+            GFF,
+            &mut contigs,
+            AnnotationFormat::Gff,
+            &types(&["gene"]),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(gff, bed);
+
+        let args = Args {
+            absolute: true,
+            cutoff: 100,
+            window_size: 5,
+            window_step: 5,
+            ..Default::default()
+        };
+        let site = |location: i32| MethylationSite {
+            chromosome: 0,
+            location,
+            strand: Strand::Sense,
+            context: Context::CG,
+            methylated: 1,
+            total: 2,
+            posterior: None,
+            status: None,
+            level: 0.5,
+            original: String::new(),
+        };
+        for location in [3529, 3530, 3630, 3631, 3632, 5000, 5899, 5900, 5999, 6000] {
+            let mut in_bed = Windows::new(2269, &args);
+            let mut in_gff = Windows::new(2269, &args);
+            assert_eq!(
+                site(location).place_in_windows(&bed, &mut in_bed).unwrap(),
+                site(location).place_in_windows(&gff, &mut in_gff).unwrap(),
+                "{location}"
+            );
+        }
+    }
+
+    #[test]
+    fn load_bed_with_track_line() {
+        // Neither the extension nor the track and browser lines tell the format, the first gene does
+        let path = std::env::temp_dir().join("extractor_genes.txt");
+        fs::write(
+            &path,
+            "browser position 1:100-200\ntrack name=genes\n1\t100\t200\tAT1G01010\t0\t+\n",
+        )
+        .unwrap();
+        let args = Args {
+            genome: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let mut contigs = Contigs::new();
+        let genes = load_genes(&args, &mut contigs).unwrap();
+        assert_eq!(genes.len(), 1);
+        assert_eq!(genes[0].name, "AT1G01010");
+        assert_eq!(contigs.len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_bed_line_does_not_panic() {
+        assert!(Gene::from_annotation_file_line(
+            "1\tstart\t200\tname\t0\t+",
+            &mut Contigs::new(),
+            false
+        )
+        .is_none());
+        assert!(
+            Gene::from_annotation_file_line("1\t100\t200", &mut Contigs::new(), false).is_none()
+        );
+    }
+}
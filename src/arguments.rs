@@ -1,17 +1,89 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::{
+    error::Error,
+    feature_sets::FeatureFile,
+    structs::{Context, Result},
+    windows::{Layout, RegionLayout},
+};
+
+// Value types of the arguments, public so that `Args` can be built outside of the command line
+pub use crate::{
+    annotation::AnnotationFormat,
+    flanks::Truncation,
+    formats::MethylomeFormat,
+    overlap::OverlapPolicy,
+    windows::{Anchor, WindowMode},
+};
+
+/// Size of the gene body windows in percent in metagene mode, unless given with --gene-window-size
+const METAGENE_GENE_WINDOW_SIZE: i32 = 5;
+
+/// Command line of the extractor: extract the sites of methylomes into windows, or build binary caches of methylomes with `extractor index`
+#[derive(Parser, Debug, Clone)]
+#[command(
+    author,
+    version,
+    about = "simple tool to separate a methylome by position within a gene",
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub args: Args,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Convert the methylome files of a directory into binary caches next to them, which later runs read instead of parsing the text files
+    Index(IndexArgs),
+}
+
+/// Arguments of `extractor index`
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct IndexArgs {
+    /// Path of directory containing the methylome files to cache
+    #[arg(short, long)]
+    pub methylome: String,
+
+    /// Format of the methylome files, detected from the first line of each file that is a site or the methimpute header by default
+    #[arg(long, value_enum, default_value_t = MethylomeFormat::Auto)]
+    pub format: MethylomeFormat,
+}
 
 /// simple tool to separate a methylome by position within a gene
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// Path of directory containing the methlyome files from which to extract the CG-sites
-    #[arg(short, long)]
+    // The empty defaults of required arguments are only used with a subcommand, which does not need them
+    #[arg(
+        short,
+        long,
+        required = true,
+        default_value = "",
+        hide_default_value = true
+    )]
     pub methylome: String,
 
-    /// Path of the annotation file containing information about beginning and end of gbM-genes
-    #[arg(short, long)]
+    /// Path of the annotation file containing information about beginning and end of gbM-genes, either six-column BED, BED12, GFF3 or GTF. Not needed if --features are given
+    #[arg(
+        short,
+        long,
+        default_value = "",
+        hide_default_value = true,
+        required_unless_present_any = ["features", "tiles"]
+    )]
     pub genome: String,
 
+    /// Further labelled annotation files, e.g. `--features te=TEs.gff --features dmr=DMRs.bed`. Every feature set gets its own window tree, summary and profile in a subdirectory of the output directory named by its label, all filled in a single pass over each methylome file. The annotation of --genome is then labelled genes
+    #[arg(long)]
+    pub features: Vec<FeatureFile>,
+
     /// Size of the window in percent of the gbM-gene length or in basepair number if --absolute is supplied
     #[arg(short, long, default_value_t = 5)]
     pub window_size: i32,
@@ -21,7 +93,13 @@ pub struct Args {
     pub window_step: i32,
 
     /// Path of the directory where extracted segments shall be stored
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        required = true,
+        default_value = "",
+        hide_default_value = true
+    )]
     pub output_dir: String,
 
     /// Use absolute length in base-pairs for window size instead of percentage of gene length
@@ -32,7 +110,328 @@ pub struct Args {
     #[arg(short, long, default_value_t = 2048)]
     pub cutoff: i32,
 
+    /// Metagene layout: the gene body in percent of the gene length, the flanks in bp. The window size and step apply to the flanks, the gene body is divided into 5% windows unless --gene-window-size is given
+    #[arg(long, default_value_t = false)]
+    pub metagene: bool,
+
+    /// Profile anchored at the transcription start or end site: sites are binned by their strand-aware distance to the anchor, from the upstream length before to the downstream length after it, in windows of the window size in bp
+    #[arg(long, value_enum, conflicts_with_all = ["metagene", "invert"])]
+    pub anchor: Option<Anchor>,
+
+    /// Number of basepairs to include upstream of the gene, defaults to the cutoff
+    #[arg(long)]
+    pub upstream_length: Option<i32>,
+
+    /// Number of basepairs to include downstream of the gene, defaults to the cutoff
+    #[arg(long)]
+    pub downstream_length: Option<i32>,
+
+    /// Window size of the upstream region, defaults to the window size
+    #[arg(long)]
+    pub upstream_window_size: Option<i32>,
+
+    /// Window step of the upstream region, defaults to the window step
+    #[arg(long)]
+    pub upstream_window_step: Option<i32>,
+
+    /// Whether the upstream windows are measured in bp or in percent of the flank, defaults to --absolute
+    #[arg(long, value_enum)]
+    pub upstream_mode: Option<WindowMode>,
+
+    /// Window size of the gene body, defaults to the window size
+    #[arg(long)]
+    pub gene_window_size: Option<i32>,
+
+    /// Window step of the gene body, defaults to the window step
+    #[arg(long)]
+    pub gene_window_step: Option<i32>,
+
+    /// Whether the gene windows are measured in bp or in percent of the gene length, defaults to --absolute
+    #[arg(long, value_enum)]
+    pub gene_mode: Option<WindowMode>,
+
+    /// Window size of the downstream region, defaults to the window size
+    #[arg(long)]
+    pub downstream_window_size: Option<i32>,
+
+    /// Window step of the downstream region, defaults to the window step
+    #[arg(long)]
+    pub downstream_window_step: Option<i32>,
+
+    /// Whether the downstream windows are measured in bp or in percent of the flank, defaults to --absolute
+    #[arg(long, value_enum)]
+    pub downstream_mode: Option<WindowMode>,
+
+    /// Write a gene-by-window matrix of every methylome to {sample}_matrix.tsv in the directory of each context, in sparse long format with one row per gene and window containing sites: the gene name and location, the window index on the axis from upstream to downstream, the sites, the coverage and the weighted methylation level
+    #[arg(long, default_value_t = false)]
+    pub matrix: bool,
+
+    /// Write a gene-level table, genes.tsv, with one row per gene and methylome: the number of sites and covered sites in the gene body, the weighted methylation level of the upstream flank, body and downstream flank and the fraction of methylated sites in the body. Genes without any sites are included
+    #[arg(long, default_value_t = false)]
+    pub gene_summary: bool,
+
+    /// Contig lengths (`.fai` index or chrom.sizes file) for tiling every contig into fixed-size bins, independent of the annotation. The methylation levels of every bin are written to tiles.tsv
+    #[arg(long)]
+    pub tiles: Option<String>,
+
+    /// Size of the genome-wide tiles in bp
+    #[arg(long, default_value_t = 10_000)]
+    pub tile_size: i32,
+
+    /// Step between the starts of the genome-wide tiles in bp. Default value is tile-size, so no overlap happens
+    #[arg(long, default_value_t = 0)]
+    pub tile_step: i32,
+
+    /// Number of sites buffered per feature set and context before they are written to the window files. Bounds the memory used for large methylomes, 0 buffers all sites of a methylome
+    #[arg(long, default_value_t = DEFAULT_BUFFER_SIZE)]
+    pub buffer_size: usize,
+
+    /// Number of threads. Methylome files are read in parallel, and split into chunks if there are fewer files than threads. Default value uses all cores
+    #[arg(long, default_value_t = 0)]
+    pub threads: usize,
+
+    /// Replace the content of the output directory, if it holds the results of a previous run
+    #[arg(short, long, default_value_t = false)]
+    pub force: bool,
+
+    /// Print the planned directory tree, number of windows and matched methylome files without writing anything
+    #[arg(long, default_value_t = false)]
+    pub dry_run: bool,
+
+    /// Truncate the upstream and downstream flanks of each gene at the nearest neighbouring gene, on any strand or only on the same strand. The effective flank lengths are written to flanks.tsv
+    #[arg(long, value_enum, num_args = 0..=1, default_missing_value = "any-strand")]
+    pub truncate_flanks: Option<Truncation>,
+
     /// Invert strands, to switch from 5' to 3' and vice versa
     #[arg(short, long, default_value_t = false)]
     pub invert: bool,
+
+    /// Pedigree file describing the nodes (id, generation, lineage, methylome file) and edges of the pedigree. If given, AlphaBeta nodelist.fn and edgelist.fn files are written into every window directory
+    #[arg(long)]
+    pub pedigree: Option<String>,
+
+    /// Estimate epimutation rates (alpha, beta) of every window from the divergence between the methylomes of the pedigree, instead of running AlphaBeta on each window directory
+    #[arg(long, default_value_t = false, requires = "pedigree")]
+    pub epimutation: bool,
+
+    /// How to assign sites overlapping more than one gene (including the upstream and downstream regions): to all of them, to the gene with the closest TSS, preferring gene bodies over flanks, or discarding them
+    #[arg(long, value_enum, default_value_t = OverlapPolicy::All)]
+    pub overlap: OverlapPolicy,
+
+    /// Sequence contexts to extract, either a comma-separated list of CG, CHG and CHH or all. Each context is written to its own subdirectory of the output directory
+    #[arg(long, value_delimiter = ',', default_value = "CG")]
+    pub context: Vec<String>,
+
+    /// Format of the methylome files, detected from the first line of each file that is a site or the methimpute header by default
+    #[arg(long, value_enum, default_value_t = MethylomeFormat::Auto)]
+    pub format: MethylomeFormat,
+
+    /// File of contig name aliases, one contig per line: the name used in the annotation followed by the names used in the methylome files (e.g. `Chr1 1 chr1`)
+    #[arg(long)]
+    pub contig_aliases: Option<String>,
+
+    /// Format of the annotation file, detected from the file extension or the first line by default
+    #[arg(long, value_enum, default_value_t = AnnotationFormat::Auto)]
+    pub annotation_format: AnnotationFormat,
+
+    /// Feature types to extract from a GFF3/GTF annotation, e.g. gene,mRNA,exon,transposable_element
+    #[arg(long, value_delimiter = ',', default_value = "gene")]
+    pub feature_types: Vec<String>,
+
+    /// Attribute of a GFF3/GTF annotation used as name of a feature. By default the first present of ID, Name, gene_id, transcript_id and Parent
+    #[arg(long)]
+    pub name_attribute: Option<String>,
+
+    /// Resolve the gene bodies into exons and introns, from the exon and CDS features of a GFF3/GTF annotation or the blocks of a BED12 annotation. Gene body sites are classified as 5'UTR, CDS, 3'UTR, exon or intron, summarised per class in features.tsv, and exonic sites are additionally placed in windows over the spliced transcript
+    #[arg(long, default_value_t = false)]
+    pub transcripts: bool,
+}
+
+/// Number of sites buffered per feature set and context by default, see `Args::buffer_size`
+pub const DEFAULT_BUFFER_SIZE: usize = 100_000;
+
+/// Empty arguments for library use, with the buffer size of the command line so that sites are written to the window files as they are by the command line tool
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            methylome: Default::default(),
+            genome: Default::default(),
+            features: Default::default(),
+            window_size: Default::default(),
+            window_step: Default::default(),
+            output_dir: Default::default(),
+            absolute: Default::default(),
+            cutoff: Default::default(),
+            metagene: Default::default(),
+            anchor: Default::default(),
+            upstream_length: Default::default(),
+            downstream_length: Default::default(),
+            upstream_window_size: Default::default(),
+            upstream_window_step: Default::default(),
+            upstream_mode: Default::default(),
+            gene_window_size: Default::default(),
+            gene_window_step: Default::default(),
+            gene_mode: Default::default(),
+            downstream_window_size: Default::default(),
+            downstream_window_step: Default::default(),
+            downstream_mode: Default::default(),
+            matrix: Default::default(),
+            gene_summary: Default::default(),
+            tiles: Default::default(),
+            tile_size: Default::default(),
+            tile_step: Default::default(),
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            threads: Default::default(),
+            force: Default::default(),
+            dry_run: Default::default(),
+            truncate_flanks: Default::default(),
+            invert: Default::default(),
+            pedigree: Default::default(),
+            epimutation: Default::default(),
+            overlap: Default::default(),
+            context: Default::default(),
+            format: Default::default(),
+            contig_aliases: Default::default(),
+            annotation_format: Default::default(),
+            feature_types: Default::default(),
+            name_attribute: Default::default(),
+            transcripts: Default::default(),
+        }
+    }
+}
+
+impl Args {
+    /// The selected sequence contexts, in the order they were given. Defaults to CG if none are selected.
+    pub fn contexts(&self) -> Result<Vec<Context>> {
+        if self.context.is_empty() {
+            return Ok(vec![Context::CG]);
+        }
+        if self.context.iter().any(|c| c.eq_ignore_ascii_case("all")) {
+            return Ok(vec![Context::CG, Context::CHG, Context::CHH]);
+        }
+        let mut contexts = Vec::new();
+        for context in &self.context {
+            let context = context.parse::<Context>()?;
+            if !contexts.contains(&context) {
+                contexts.push(context);
+            }
+        }
+        Ok(contexts)
+    }
+
+    /// Arguments of every annotation file of a run, with its label: the annotation of --genome written to the output directory, or, with --features, every labelled annotation written to a subdirectory of the output directory. The annotation of --genome is then labelled `genes`.
+    pub fn feature_sets(&self) -> Result<Vec<(Option<String>, Args)>> {
+        if self.features.is_empty() {
+            // Runs with only genome-wide tiles have no annotation
+            if self.genome.is_empty() {
+                return Ok(Vec::new());
+            }
+            return Ok(vec![(None, self.clone())]);
+        }
+        let mut files = self.features.clone();
+        if !self.genome.is_empty() {
+            files.insert(
+                0,
+                FeatureFile {
+                    label: String::from("genes"),
+                    path: self.genome.clone(),
+                },
+            );
+        }
+        let mut sets: Vec<(Option<String>, Args)> = Vec::new();
+        for file in files {
+            if sets
+                .iter()
+                .any(|(label, _)| label.as_ref() == Some(&file.label))
+            {
+                return Err(Error::FeatureSet(format!(
+                    "the label {} is used more than once",
+                    file.label
+                )));
+            }
+            let args = Args {
+                genome: file.path,
+                output_dir: format!("{}/{}", self.output_dir, file.label),
+                features: Vec::new(),
+                ..self.clone()
+            };
+            sets.push((Some(file.label), args));
+        }
+        Ok(sets)
+    }
+
+    /// Window layout of the upstream, gene and downstream region. Region-specific lengths, window sizes, steps and modes fall back to the cutoff, the shared window size and step and --absolute.
+    /// Without any step, the windows of a region do not overlap.
+    ///
+    /// In metagene mode, the flanks default to absolute and the gene body to relative windows. The shared window size and step are in bp then and only apply to the flanks.
+    pub fn layout(&self, max_gene_length: i32) -> Layout {
+        let shared_step = Some(self.window_step).filter(|s| *s != 0);
+        let flank_defaults = (
+            self.window_size,
+            shared_step,
+            self.absolute || self.metagene,
+        );
+        let gene_defaults = if self.metagene {
+            (METAGENE_GENE_WINDOW_SIZE, None, false)
+        } else {
+            (self.window_size, shared_step, self.absolute)
+        };
+        // Region-specific values, falling back to the defaults (size, step, absolute)
+        let region = |length: i32,
+                      size: Option<i32>,
+                      step: Option<i32>,
+                      mode: Option<WindowMode>,
+                      defaults: (i32, Option<i32>, bool)| {
+            let size = size.unwrap_or(defaults.0);
+            RegionLayout {
+                length,
+                size,
+                step: step.or(defaults.1).unwrap_or(size),
+                absolute: mode.map_or(defaults.2, |m| m == WindowMode::Absolute),
+                origin: 0,
+            }
+        };
+        let upstream = region(
+            self.upstream_length.unwrap_or(self.cutoff),
+            self.upstream_window_size,
+            self.upstream_window_step,
+            self.upstream_mode,
+            flank_defaults,
+        );
+        let downstream = region(
+            self.downstream_length.unwrap_or(self.cutoff),
+            self.downstream_window_size,
+            self.downstream_window_step,
+            self.downstream_mode,
+            flank_defaults,
+        );
+        let gene = match self.anchor {
+            // One axis in bp from the upstream length before to the downstream length after the anchor
+            Some(_) => RegionLayout {
+                length: upstream.length + downstream.length,
+                origin: -upstream.length,
+                ..region(
+                    0,
+                    self.gene_window_size,
+                    self.gene_window_step,
+                    Some(WindowMode::Absolute),
+                    flank_defaults,
+                )
+            },
+            None => region(
+                max_gene_length,
+                self.gene_window_size,
+                self.gene_window_step,
+                self.gene_mode,
+                gene_defaults,
+            ),
+        };
+        Layout {
+            upstream,
+            gene,
+            downstream,
+            anchor: self.anchor,
+            spliced: self.transcripts,
+        }
+    }
 }
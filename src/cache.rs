@@ -0,0 +1,632 @@
+use std::{
+    fs::{File, Metadata},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    time::UNIX_EPOCH,
+};
+
+use flate2::Crc;
+
+use crate::*;
+
+/// File name suffix of the binary caches, written next to the methylome files by `extractor index`
+pub const CACHE_SUFFIX: &str = ".cache";
+
+/// Magic bytes and version at the start of every cache
+const CACHE_MAGIC: [u8; 8] = *b"XTRCACH2";
+
+/// Width in bytes of the columns of a cache: contig, position, strand, context, methylated, total, posterior, status, level and the offset of the line of the site.
+/// The lines of the sites follow the columns, so that the window files of a cached methylome are the same as those of the text file.
+const COLUMN_WIDTHS: [u64; 10] = [4, 4, 1, 1, 4, 4, 4, 1, 4, 8];
+
+/// Sites of a methylome file or of one chunk of it
+pub enum Methylome {
+    /// Lines of a methylome in one of the text formats, parsed as they are read
+    Text(MethylomeReader),
+    /// Sites of the binary cache of a methylome
+    Cached(CachedSites),
+}
+
+impl Methylome {
+    /// The sites of the methylome with whether their format knows the strand. Lines that are not sites, e.g. header rows, are skipped.
+    /// The file name is only used to report a methylome of an unknown format.
+    pub fn sites<'a>(
+        self,
+        filename: &'a OsString,
+        contigs: &'a Contigs,
+        args: &'a Args,
+    ) -> Box<dyn Iterator<Item = Result<(MethylationSite, bool)>> + 'a> {
+        match self {
+            Methylome::Text(reader) => {
+                let mut format = args.format;
+                let mut lines = reader.lines();
+                // Whether a line could not be classified, reported if the format is never detected
+                let mut unknown = false;
+                Box::new(std::iter::from_fn(move || loop {
+                    let Some(line) = lines.next() else {
+                        return std::mem::take(&mut unknown).then(|| {
+                            Err(Error::UnknownFormat(
+                                filename.to_string_lossy().into_owned(),
+                            ))
+                        });
+                    };
+                    let Ok(line) = line else {
+                        continue;
+                    };
+                    if format == MethylomeFormat::Auto {
+                        // Lines before the first site, e.g. a track line, are skipped
+                        match MethylomeFormat::detect(&line) {
+                            Some(detected) => {
+                                format = detected;
+                                unknown = false;
+                            }
+                            None => {
+                                unknown = true;
+                                continue;
+                            }
+                        }
+                    }
+                    // If a site could not be extracted from a line, continue with the next line. Happens on header rows, for example.
+                    if let Ok(site) = format.parse_line(&line, contigs, args.invert) {
+                        return Some(Ok((site, format.is_stranded())));
+                    }
+                }))
+            }
+            Methylome::Cached(sites) => Box::new(sites.with_contigs(contigs, args.invert)),
+        }
+    }
+}
+
+/// Size, modification time and checksum of the methylome file a cache was built from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Source {
+    size: u64,
+    modified: (u64, u32),
+    checksum: u32,
+}
+
+impl Source {
+    fn modified(metadata: &Metadata) -> Result<(u64, u32)> {
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Ok((modified.as_secs(), modified.subsec_nanos()))
+    }
+
+    fn checksum(path: &Path) -> Result<u32> {
+        let mut file = File::open(path)?;
+        let mut crc = Crc::new();
+        let mut buffer = vec![0; 1 << 16];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(crc.sum());
+            }
+            crc.update(&buffer[..read]);
+        }
+    }
+
+    fn of_file(path: &Path) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Source {
+            size: metadata.len(),
+            modified: Source::modified(&metadata)?,
+            checksum: Source::checksum(path)?,
+        })
+    }
+
+    /// Whether a file is still the source of the cache. Files of the same size with another modification time, e.g. copies, are compared by their checksum.
+    fn matches(&self, path: &Path) -> Result<bool> {
+        let metadata = fs::metadata(path)?;
+        if metadata.len() != self.size {
+            return Ok(false);
+        }
+        if Source::modified(&metadata)? == self.modified {
+            return Ok(true);
+        }
+        Ok(Source::checksum(path)? == self.checksum)
+    }
+}
+
+/// Header of a cache, followed by the columns of the sites
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Header {
+    source: Source,
+    format: MethylomeFormat,
+    /// Names of the contigs of the methylome, indexed by the contig column
+    names: Vec<String>,
+    sites: u64,
+    /// Length in bytes of the lines of the sites
+    lines: u64,
+}
+
+impl Header {
+    fn write(&self, writer: &mut impl Write) -> Result<()> {
+        writer.write_all(&CACHE_MAGIC)?;
+        writer.write_all(&self.source.size.to_le_bytes())?;
+        writer.write_all(&self.source.modified.0.to_le_bytes())?;
+        writer.write_all(&self.source.modified.1.to_le_bytes())?;
+        writer.write_all(&self.source.checksum.to_le_bytes())?;
+        writer.write_all(&[match self.format {
+            MethylomeFormat::Auto => 0,
+            MethylomeFormat::Methimpute => 1,
+            MethylomeFormat::Coverage => 2,
+            MethylomeFormat::CxReport => 3,
+        }])?;
+        writer.write_all(&(self.names.len() as u32).to_le_bytes())?;
+        for name in &self.names {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        writer.write_all(&self.sites.to_le_bytes())?;
+        writer.write_all(&self.lines.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read the header, returning it with its length in bytes
+    fn read(reader: &mut impl Read) -> Result<(Self, u64)> {
+        let invalid = || Error::Cache(String::from("not a methylome cache of this version"));
+        let mut bytes = |length: usize| -> Result<Vec<u8>> {
+            let mut buffer = vec![0; length];
+            reader.read_exact(&mut buffer).map_err(|_| invalid())?;
+            Ok(buffer)
+        };
+        let le32 = |b: Vec<u8>| u32::from_le_bytes(b.try_into().unwrap());
+        let le64 = |b: Vec<u8>| u64::from_le_bytes(b.try_into().unwrap());
+        if bytes(8)? != CACHE_MAGIC {
+            return Err(invalid());
+        }
+        let source = Source {
+            size: le64(bytes(8)?),
+            modified: (le64(bytes(8)?), le32(bytes(4)?)),
+            checksum: le32(bytes(4)?),
+        };
+        let format = match bytes(1)?[0] {
+            1 => MethylomeFormat::Methimpute,
+            2 => MethylomeFormat::Coverage,
+            3 => MethylomeFormat::CxReport,
+            _ => return Err(invalid()),
+        };
+        let count = le32(bytes(4)?);
+        let mut length = 8 + 8 + 8 + 4 + 4 + 1 + 4 + 8 + 8;
+        let mut names = Vec::new();
+        for _ in 0..count {
+            let name_length = le32(bytes(4)?) as usize;
+            names.push(String::from_utf8(bytes(name_length)?).map_err(|_| invalid())?);
+            length += 4 + name_length as u64;
+        }
+        let sites = le64(bytes(8)?);
+        let lines = le64(bytes(8)?);
+        let header = Header {
+            source,
+            format,
+            names,
+            sites,
+            lines,
+        };
+        Ok((header, length))
+    }
+}
+
+/// Path of the cache of a methylome file
+pub fn cache_path(path: &Path) -> PathBuf {
+    let mut cache = path.as_os_str().to_owned();
+    cache.push(CACHE_SUFFIX);
+    PathBuf::from(cache)
+}
+
+/// Temporary files that are removed when dropped, so that they are also cleaned up if writing a cache fails
+struct TempFiles(Vec<PathBuf>);
+
+impl Drop for TempFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Convert a methylome file into a binary cache next to it, storing the parsed fields of every site column by column, followed by the lines of the sites.
+/// The columns and lines are written to temporary files first, so that memory does not grow with the size of the methylome.
+/// Returns the number of cached sites.
+pub fn write_cache(path: &PathBuf, filename: &OsString, format: MethylomeFormat) -> Result<u64> {
+    let cache = cache_path(path);
+    // Named like caches, so that they are not taken for methylome files
+    let column_paths = TempFiles(
+        (0..=COLUMN_WIDTHS.len())
+            .map(|i| {
+                let mut column = path.as_os_str().to_owned();
+                column.push(format!(".column{i}{CACHE_SUFFIX}"));
+                PathBuf::from(column)
+            })
+            .collect(),
+    );
+    let mut columns = column_paths
+        .0
+        .iter()
+        .map(|path| Ok(BufWriter::new(File::create(path)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut contigs = Contigs::new();
+    let mut format = format;
+    let mut sites = 0;
+    let mut offset: u64 = 0;
+    for line in open_file(path, filename)?.lines() {
+        let Ok(line) = line else {
+            continue;
+        };
+        if format == MethylomeFormat::Auto {
+            // Lines before the first site, e.g. a track line, are skipped
+            let Some(detected) = MethylomeFormat::detect(&line) else {
+                continue;
+            };
+            format = detected;
+        }
+        // All formats start with the contig, which is only interned once a line of it is a site, so that header and track lines do not become contigs
+        let contig = line.split('\t').next().unwrap_or_default();
+        if contigs.get(contig).is_none() {
+            let mut new = Contigs::new();
+            new.intern(contig);
+            if format.parse_line(&line, &new, false).is_err() {
+                continue;
+            }
+            contigs.intern(contig);
+        }
+        let Ok(site) = format.parse_line(&line, &contigs, false) else {
+            continue;
+        };
+        // Parsed with the strand as given, like the lines of the text file
+        let line = format!("{}\n", site.original);
+        let fields: [&[u8]; 11] = [
+            &(site.chromosome as u32).to_le_bytes(),
+            &site.location.to_le_bytes(),
+            &[match site.strand {
+                Strand::Sense => 0,
+                Strand::Antisense => 1,
+            }],
+            &[match site.context {
+                Context::CG => 0,
+                Context::CHG => 1,
+                Context::CHH => 2,
+            }],
+            &site.methylated.to_le_bytes(),
+            &site.total.to_le_bytes(),
+            &site.posterior.unwrap_or(f32::NAN).to_le_bytes(),
+            &[match site.status {
+                None => 0,
+                Some(Status::Unmethylated) => 1,
+                Some(Status::Intermediate) => 2,
+                Some(Status::Methylated) => 3,
+            }],
+            &site.level.to_le_bytes(),
+            &offset.to_le_bytes(),
+            line.as_bytes(),
+        ];
+        for (column, field) in columns.iter_mut().zip(fields) {
+            column.write_all(field)?;
+        }
+        sites += 1;
+        offset += line.len() as u64;
+    }
+    if format == MethylomeFormat::Auto {
+        return Err(Error::UnknownFormat(
+            filename.to_string_lossy().into_owned(),
+        ));
+    }
+    for column in &mut columns {
+        column.flush()?;
+    }
+    drop(columns);
+
+    let header = Header {
+        source: Source::of_file(path)?,
+        format,
+        names: (0..contigs.len())
+            .map(|id| contigs.name(id).to_owned())
+            .collect(),
+        sites,
+        lines: offset,
+    };
+    let mut writer = BufWriter::new(File::create(&cache)?);
+    header.write(&mut writer)?;
+    for column in &column_paths.0 {
+        io::copy(&mut File::open(column)?, &mut writer)?;
+    }
+    writer.flush()?;
+    Ok(sites)
+}
+
+/// Open the cache of a methylome file as up to `count` chunks of about the same number of sites, in the order of the file.
+/// Returns `None` if there is no cache, if the methylome changed since the cache was written or if the cache was written in another format than an explicit `format`.
+pub fn open_cache(
+    path: &Path,
+    count: usize,
+    format: MethylomeFormat,
+) -> Result<Option<Vec<CachedSites>>> {
+    let cache = cache_path(path);
+    if !cache.exists() {
+        return Ok(None);
+    }
+    let (header, header_length) = Header::read(&mut BufReader::new(File::open(&cache)?))?;
+    let expected = header_length + header.sites * COLUMN_WIDTHS.iter().sum::<u64>() + header.lines;
+    if fs::metadata(&cache)?.len() != expected {
+        return Err(Error::Cache(format!(
+            "{} is truncated, please rebuild it with extractor index",
+            cache.display()
+        )));
+    }
+    if !header.source.matches(path)? {
+        println!(
+            "{} is older than its methylome and is not used, please rebuild it with extractor index",
+            cache.display()
+        );
+        return Ok(None);
+    }
+    if format != MethylomeFormat::Auto && format != header.format {
+        println!(
+            "{} was written for the {:?} format and is not used with --format {:?}",
+            cache.display(),
+            header.format,
+            format
+        );
+        return Ok(None);
+    }
+
+    let count = count.clamp(1, header.sites.max(1) as usize) as u64;
+    (0..count)
+        .map(|chunk| {
+            let (start, end) = (
+                header.sites * chunk / count,
+                header.sites * (chunk + 1) / count,
+            );
+            let mut offset = header_length;
+            let mut columns = Vec::new();
+            for width in COLUMN_WIDTHS {
+                let mut file = File::open(&cache)?;
+                file.seek(SeekFrom::Start(offset + start * width))?;
+                columns.push(BufReader::new(file));
+                offset += header.sites * width;
+            }
+            // The lines of the range start at the line offset of its first site
+            let mut line_offset = [0; 8];
+            if start < end {
+                let mut file = File::open(&cache)?;
+                file.seek(SeekFrom::Start(offset - header.sites * 8 + start * 8))?;
+                file.read_exact(&mut line_offset)?;
+            }
+            let mut lines = File::open(&cache)?;
+            lines.seek(SeekFrom::Start(offset + u64::from_le_bytes(line_offset)))?;
+            Ok(CachedSites {
+                names: header.names.clone(),
+                format: header.format,
+                remaining: end - start,
+                columns,
+                lines: BufReader::new(lines),
+            })
+        })
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+/// Sites of a range of a cache, read column by column
+pub struct CachedSites {
+    names: Vec<String>,
+    format: MethylomeFormat,
+    /// Number of sites of the range that are not read yet
+    remaining: u64,
+    /// Readers of the columns, positioned at the next site
+    columns: Vec<BufReader<File>>,
+    /// Reader of the lines, positioned at the line of the next site
+    lines: BufReader<File>,
+}
+
+impl CachedSites {
+    /// Read the fields and the line of the next site, `None` at the end of the range
+    fn next_fields(&mut self) -> Option<io::Result<([u64; 10], String)>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let mut fields = [0; 10];
+        for ((field, column), width) in fields.iter_mut().zip(&mut self.columns).zip(COLUMN_WIDTHS)
+        {
+            let mut bytes = [0; 8];
+            if let Err(e) = column.read_exact(&mut bytes[..width as usize]) {
+                return Some(Err(e));
+            }
+            *field = u64::from_le_bytes(bytes);
+        }
+        let mut line = String::new();
+        if let Err(e) = self.lines.read_line(&mut line) {
+            return Some(Err(e));
+        }
+        line.pop();
+        Some(Ok((fields, line)))
+    }
+
+    /// The sites on the contigs of the dictionary, with their strand inverted if requested
+    fn with_contigs(
+        mut self,
+        contigs: &Contigs,
+        invert_strand: bool,
+    ) -> impl Iterator<Item = Result<(MethylationSite, bool)>> + '_ {
+        let ids: Vec<Option<ContigId>> = self.names.iter().map(|name| contigs.get(name)).collect();
+        std::iter::from_fn(move || loop {
+            let (fields, line) = match self.next_fields()? {
+                Ok(next) => next,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let [contig, location, strand, context, methylated, total, posterior, status, level, _] =
+                fields.map(|field| field as u32);
+            // Sites on contigs without features can not belong to any of them, like in text files
+            let Some(chromosome) = ids.get(contig as usize).copied().flatten() else {
+                continue;
+            };
+            let sense = (strand == 0) != invert_strand;
+            let posterior = f32::from_bits(posterior);
+            let mut site = MethylationSite {
+                chromosome,
+                location: location as i32,
+                strand: if sense {
+                    Strand::Sense
+                } else {
+                    Strand::Antisense
+                },
+                context: match context {
+                    0 => Context::CG,
+                    1 => Context::CHG,
+                    _ => Context::CHH,
+                },
+                methylated,
+                total,
+                posterior: (!posterior.is_nan()).then_some(posterior),
+                status: match status {
+                    1 => Some(Status::Unmethylated),
+                    2 => Some(Status::Intermediate),
+                    3 => Some(Status::Methylated),
+                    _ => None,
+                },
+                level: f32::from_bits(level),
+                original: line,
+            };
+            // Lines of Bismark files are rendered from the site, with the strand after inversion like in text files
+            if invert_strand && self.format != MethylomeFormat::Methimpute {
+                site.original = site.to_methimpute_line(&self.names[contig as usize]);
+            }
+            return Some(Ok((site, self.format.is_stranded())));
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const METHYLOME: &str = "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl
+1\t80\t+\tCG\t0\t8\t0.9999\tU\t0.0025
+2\t150\t-\tCHH\t6\t8\tNA\tNA\t0.7500
+1\t150\t+\tCG\t1\t3\t0.999999881\tM\t0.333333333
+1\t260\t+\tCG\t6\t8\t0.9999\tM\t0.7500
+";
+
+    const COVERAGE: &str = "1\t80\t80\t0\t0\t8
+1\t150\t150\t33.333333\t1\t2
+";
+
+    /// Sites of a methylome read from its text and from its cache, split into two chunks
+    fn read_both(
+        path: &PathBuf,
+        filename: &OsString,
+        args: &Args,
+    ) -> (Vec<MethylationSite>, Vec<MethylationSite>) {
+        let mut contigs = Contigs::new();
+        contigs.intern("1");
+        contigs.intern("2");
+        let text = Methylome::Text(open_file(path, filename).unwrap())
+            .sites(filename, &contigs, args)
+            .map(|site| site.unwrap().0)
+            .collect();
+        // The sites are read in the order of the file
+        let cached = open_cache(path, 2, args.format)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .flat_map(|chunk| Methylome::Cached(chunk).sites(filename, &contigs, args))
+            .map(|site| site.unwrap().0)
+            .collect();
+        (text, cached)
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let path = std::env::temp_dir().join("extractor_cache.txt");
+        fs::write(&path, METHYLOME).unwrap();
+        let filename = OsString::from("extractor_cache.txt");
+        assert_eq!(
+            write_cache(&path, &filename, MethylomeFormat::Auto).unwrap(),
+            4
+        );
+        // The header row is not a contig
+        let mut cache = File::open(cache_path(&path)).unwrap();
+        let (header, _) = Header::read(&mut cache).unwrap();
+        assert_eq!(header.names, ["1", "2"]);
+
+        // The lines of the sites are kept as they are, with the strand as given and all digits of the level
+        for invert in [false, true] {
+            let args = Args {
+                invert,
+                ..Default::default()
+            };
+            let (text, cached) = read_both(&path, &filename, &args);
+            assert_eq!(cached.len(), 4);
+            assert_eq!(cached, text);
+            assert_eq!(
+                cached[2].original,
+                "1\t150\t+\tCG\t1\t3\t0.999999881\tM\t0.333333333"
+            );
+        }
+
+        // Sites on contigs unknown to the run are skipped
+        let mut contigs = Contigs::new();
+        contigs.intern("2");
+        let cached = Methylome::Cached(
+            open_cache(&path, 1, MethylomeFormat::Auto)
+                .unwrap()
+                .unwrap()
+                .remove(0),
+        );
+        assert_eq!(
+            cached.sites(&filename, &contigs, &Args::default()).count(),
+            1
+        );
+
+        // An explicit format other than that of the cache reads the text instead
+        let open = |format| open_cache(&path, 1, format).unwrap().is_some();
+        assert!(open(MethylomeFormat::Methimpute));
+        assert!(!open(MethylomeFormat::Coverage));
+
+        // A changed methylome is not read from the stale cache
+        fs::write(&path, METHYLOME.replace("0.9999", "0.99990")).unwrap();
+        assert!(open_cache(&path, 1, MethylomeFormat::Auto)
+            .unwrap()
+            .is_none());
+        fs::remove_file(cache_path(&path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn cache_rendered_lines() {
+        let path = std::env::temp_dir().join("extractor_cache.cov");
+        fs::write(&path, COVERAGE).unwrap();
+        let filename = OsString::from("extractor_cache.cov");
+        write_cache(&path, &filename, MethylomeFormat::Auto).unwrap();
+
+        // Lines of Bismark files are rendered in the methimpute layout, with the strand after inversion
+        for invert in [false, true] {
+            let args = Args {
+                invert,
+                ..Default::default()
+            };
+            let (text, cached) = read_both(&path, &filename, &args);
+            assert_eq!(cached.len(), 2);
+            assert_eq!(cached, text);
+        }
+        fs::remove_file(cache_path(&path)).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn cache_failure_removes_columns() {
+        let path = std::env::temp_dir().join("extractor_cache_unknown.cov");
+        fs::write(&path, "track type=bedGraph\n").unwrap();
+        let filename = OsString::from("extractor_cache_unknown.cov");
+        assert!(write_cache(&path, &filename, MethylomeFormat::Auto).is_err());
+
+        // The temporary columns are removed although no cache was written
+        let mut column = path.as_os_str().to_owned();
+        column.push(format!(".column0{CACHE_SUFFIX}"));
+        assert!(!PathBuf::from(column).exists());
+        assert!(!cache_path(&path).exists());
+        fs::remove_file(path).unwrap();
+    }
+}
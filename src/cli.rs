@@ -1,9 +1,16 @@
 use clap::Parser;
-use extractor::{arguments::Args, extract};
+use extractor::{
+    arguments::{Cli, Command},
+    extract, index,
+};
 
 fn main() {
-    let args = Args::parse();
-    match extract(args) {
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Some(Command::Index(args)) => index(args),
+        None => extract(cli.args),
+    };
+    match result {
         Ok(_) => println!("Done!"),
         Err(e) => println!("Error: {}", e),
     }
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use crate::*;
+
+/// Index of a contig in the `Contigs` dictionary, used to look up the genes of a contig in the genome
+pub type ContigId = usize;
+
+/// Dictionary of the sequence names (chromosomes, organelles, scaffolds, ..) of a genome.
+///
+/// Names are interned while reading the annotation and looked up while reading the methylome files. Several names can refer to the same contig through aliases, so that `1` in a methylome matches `Chr1` in the annotation.
+#[derive(Clone, Debug, Default)]
+pub struct Contigs {
+    names: Vec<String>,
+    ids: HashMap<String, ContigId>,
+}
+
+impl Contigs {
+    pub fn new() -> Self {
+        Contigs::default()
+    }
+
+    /// Read aliases from a file with one contig per line, the name used in the annotation followed by its whitespace-separated aliases (`Chr1 1 chr1`).
+    pub fn from_alias_file(path: &str) -> Result<Self> {
+        let mut contigs = Contigs::new();
+        for line in lines_from_file(path)? {
+            let line = line?;
+            let mut names = line.split_whitespace();
+            let Some(name) = names.next() else {
+                continue;
+            };
+            if name.starts_with('#') {
+                continue;
+            }
+            let id = contigs.intern(name);
+            for alias in names {
+                contigs.ids.insert(alias.to_owned(), id);
+            }
+        }
+        Ok(contigs)
+    }
+
+    /// Id of a contig, adding it to the dictionary if it is not known yet
+    pub fn intern(&mut self, name: &str) -> ContigId {
+        if let Some(id) = self.get(name) {
+            return id;
+        }
+        let id = self.names.len();
+        self.names.push(name.to_owned());
+        self.ids.insert(name.to_owned(), id);
+        id
+    }
+
+    /// Id of a contig or one of its aliases
+    pub fn get(&self, name: &str) -> Option<ContigId> {
+        self.ids.get(name).copied()
+    }
+
+    /// Id of a contig or one of its aliases, failing for contigs that are not part of the dictionary
+    pub fn id(&self, name: &str) -> Result<ContigId> {
+        self.get(name)
+            .ok_or_else(|| Error::UnknownContig(name.to_owned()))
+    }
+
+    /// Name of a contig as first seen, either in the alias file or the annotation
+    pub fn name(&self, id: ContigId) -> &str {
+        &self.names[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Contigs;
+
+    #[test]
+    fn intern_contigs() {
+        let mut contigs = Contigs::new();
+        let chr1 = contigs.intern("Chr1");
+        let scaffold = contigs.intern("scaffold_1024");
+        assert_eq!(contigs.intern("Chr1"), chr1);
+        assert_ne!(chr1, scaffold);
+        assert_eq!(contigs.name(scaffold), "scaffold_1024");
+        assert_eq!(contigs.len(), 2);
+        assert!(contigs.id("ChrM").is_err());
+    }
+
+    #[test]
+    fn many_contigs() {
+        let mut contigs = Contigs::new();
+        for i in 0..1000 {
+            contigs.intern(&format!("scaffold_{i}"));
+        }
+        assert_eq!(contigs.get("scaffold_999"), Some(999));
+    }
+
+    #[test]
+    fn alias_file() {
+        let path = std::env::temp_dir().join("extractor_contig_aliases.txt");
+        std::fs::write(&path, "# name aliases\nChr1\t1\tchr1\nChrC\tC\tPt\n").unwrap();
+        let contigs = Contigs::from_alias_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(contigs.get("1"), contigs.get("Chr1"));
+        assert_eq!(contigs.get("chr1"), contigs.get("Chr1"));
+        assert_eq!(contigs.get("Pt"), contigs.get("ChrC"));
+        assert_eq!(contigs.name(contigs.id("Pt").unwrap()), "ChrC");
+        assert_ne!(contigs.get("1"), contigs.get("C"));
+    }
+}
@@ -0,0 +1,527 @@
+use std::collections::HashMap;
+
+use rayon::prelude::*;
+
+use crate::*;
+
+/// Header of the table of estimated epimutation rates, one row per context, region and window
+pub const EPIMUTATION_HEADER: &str =
+    "context\tregion\twindow\tpairs\tsites\talpha\tbeta\tweight\tintercept\trss\n";
+
+/// Number of Nelder-Mead iterations per start of the optimizer
+const ITERATIONS: usize = 1000;
+
+/// Genotypes of a site in a diploid, selfing plant: unmethylated, heterozygous and methylated epialleles
+const GENOTYPES: usize = 3;
+
+/// Divergence between two sampled methylomes, separated by `t1` and `t2` generations from their most recent common ancestor
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub t1: u32,
+    pub t2: u32,
+    pub d: f64,
+}
+
+/// Parameters of the neutral epimutation model
+#[derive(Clone, Debug, PartialEq)]
+pub struct Estimate {
+    /// Rate of gains of methylation (u -> m) per allele and generation
+    pub alpha: f64,
+    /// Rate of losses of methylation (m -> u) per allele and generation
+    pub beta: f64,
+    /// Divergence contributed by a heterozygous site compared to a homozygous one
+    pub weight: f64,
+    /// Divergence not explained by epimutations, e.g. from sequencing or calling errors
+    pub intercept: f64,
+    /// Residual sum of squares of the fit
+    pub rss: f64,
+}
+
+/// Neutral epimutation model of a diploid, selfing pedigree (AlphaBeta style).
+///
+/// Every generation, each allele of a site gains methylation with probability alpha and loses it with probability beta. Two gametes fuse to the genotype of the next generation.
+/// The expected divergence of two samples follows from evolving the genotype distribution of their common ancestor along both branches.
+pub struct Model {
+    /// Genotype distribution (uu, um, mm) of the common ancestors
+    pub p0: [f64; GENOTYPES],
+}
+
+impl Model {
+    /// Transition probabilities between genotypes within one generation
+    fn transitions(alpha: f64, beta: f64) -> [[f64; GENOTYPES]; GENOTYPES] {
+        let mut transitions = [[0.0; GENOTYPES]; GENOTYPES];
+        for (g, row) in transitions.iter_mut().enumerate() {
+            // Probability of a methylated gamete, given the share of methylated alleles of the parent
+            let m = g as f64 / 2.0;
+            let p = m * (1.0 - beta) + (1.0 - m) * alpha;
+            *row = [(1.0 - p).powi(2), 2.0 * p * (1.0 - p), p.powi(2)];
+        }
+        transitions
+    }
+
+    /// Genotype distributions after `t` generations, for every starting genotype
+    fn evolve(
+        transitions: &[[f64; GENOTYPES]; GENOTYPES],
+        t: u32,
+    ) -> [[f64; GENOTYPES]; GENOTYPES] {
+        let mut result = [[0.0; GENOTYPES]; GENOTYPES];
+        for (g, row) in result.iter_mut().enumerate() {
+            row[g] = 1.0;
+        }
+        for _ in 0..t {
+            let mut next = [[0.0; GENOTYPES]; GENOTYPES];
+            for g in 0..GENOTYPES {
+                for h in 0..GENOTYPES {
+                    next[g][h] = (0..GENOTYPES)
+                        .map(|k| result[g][k] * transitions[k][h])
+                        .sum();
+                }
+            }
+            result = next;
+        }
+        result
+    }
+
+    /// Expected divergence of two samples `t1` and `t2` generations after their common ancestor
+    pub fn divergence(&self, estimate: &Estimate, t1: u32, t2: u32) -> f64 {
+        let w = estimate.weight;
+        let distance = [[0.0, w, 1.0], [w, 0.0, w], [1.0, w, 0.0]];
+        let transitions = Model::transitions(estimate.alpha, estimate.beta);
+        let branch1 = Model::evolve(&transitions, t1);
+        let branch2 = Model::evolve(&transitions, t2);
+
+        let mut d = 0.0;
+        for g0 in 0..GENOTYPES {
+            for g1 in 0..GENOTYPES {
+                for g2 in 0..GENOTYPES {
+                    d += self.p0[g0] * branch1[g0][g1] * branch2[g0][g2] * distance[g1][g2];
+                }
+            }
+        }
+        estimate.intercept + d
+    }
+
+    /// Fit alpha, beta, weight and intercept to the observed divergences by least squares, starting the optimizer from several rate combinations
+    pub fn fit(&self, divergences: &[Divergence]) -> Estimate {
+        let estimate = |p: &[f64]| Estimate {
+            alpha: 10f64.powf(p[0].clamp(-12.0, 0.0)),
+            beta: 10f64.powf(p[1].clamp(-12.0, 0.0)),
+            weight: 1.0 / (1.0 + (-p[2]).exp()),
+            intercept: p[3].abs(),
+            rss: 0.0,
+        };
+        let rss = |p: &[f64]| {
+            let estimate = estimate(p);
+            divergences
+                .iter()
+                .map(|d| (d.d - self.divergence(&estimate, d.t1, d.t2)).powi(2))
+                .sum::<f64>()
+        };
+        let intercept = divergences
+            .iter()
+            .map(|d| d.d)
+            .fold(f64::INFINITY, f64::min);
+
+        let starts = [-6.0, -4.0, -2.0];
+        let (best, rss) = starts
+            .iter()
+            .flat_map(|a| starts.iter().map(move |b| [*a, *b, 0.0, intercept]))
+            .map(|start| nelder_mead(rss, &start, 1.0, ITERATIONS))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        Estimate {
+            rss,
+            ..estimate(&best)
+        }
+    }
+}
+
+/// Minimize `f` with the Nelder-Mead simplex method, returning the best point and its value
+fn nelder_mead<F: Fn(&[f64]) -> f64>(
+    f: F,
+    start: &[f64],
+    step: f64,
+    iterations: usize,
+) -> (Vec<f64>, f64) {
+    let n = start.len();
+    let mut simplex: Vec<(Vec<f64>, f64)> = (0..=n)
+        .map(|i| {
+            let mut x = start.to_vec();
+            if i > 0 {
+                x[i - 1] += step;
+            }
+            let fx = f(&x);
+            (x, fx)
+        })
+        .collect();
+
+    for _ in 0..iterations {
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+        if (simplex[n].1 - simplex[0].1).abs() < 1e-15 {
+            break;
+        }
+        let centroid: Vec<f64> = (0..n)
+            .map(|j| simplex[..n].iter().map(|(x, _)| x[j]).sum::<f64>() / n as f64)
+            .collect();
+        // Point on the line from the centroid through the worst point, negative t reflects away from the worst point
+        let point = |t: f64| -> (Vec<f64>, f64) {
+            let x: Vec<f64> = centroid
+                .iter()
+                .zip(&simplex[n].0)
+                .map(|(c, w)| c + t * (w - c))
+                .collect();
+            let fx = f(&x);
+            (x, fx)
+        };
+
+        let reflected = point(-1.0);
+        if reflected.1 < simplex[0].1 {
+            let expanded = point(-2.0);
+            simplex[n] = if expanded.1 < reflected.1 {
+                expanded
+            } else {
+                reflected
+            };
+        } else if reflected.1 < simplex[n - 1].1 {
+            simplex[n] = reflected;
+        } else {
+            let contracted = if reflected.1 < simplex[n].1 {
+                point(-0.5)
+            } else {
+                point(0.5)
+            };
+            if contracted.1 < reflected.1.min(simplex[n].1) {
+                simplex[n] = contracted;
+            } else {
+                // Shrink towards the best point
+                let best = simplex[0].0.clone();
+                for (x, fx) in simplex.iter_mut().skip(1) {
+                    for j in 0..n {
+                        x[j] = best[j] + 0.5 * (x[j] - best[j]);
+                    }
+                    *fx = f(x);
+                }
+            }
+        }
+    }
+    simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+    simplex.swap_remove(0)
+}
+
+/// Methylation state of a site for divergence calculations: 0 for unmethylated, 0.5 for intermediate and 1 for methylated sites
+fn state(status: &str) -> Option<f64> {
+    match status.parse::<Status>().ok()? {
+        Status::Unmethylated => Some(0.0),
+        Status::Intermediate => Some(0.5),
+        Status::Methylated => Some(1.0),
+    }
+}
+
+/// Methylation state of a site without a status, e.g. from a Bismark file, called from its methylation level: unmethylated below 1/3, methylated above 2/3 and intermediate otherwise
+fn state_from_level(level: &str) -> Option<f64> {
+    let level = level.parse::<f64>().ok().filter(|l| !l.is_nan())?;
+    Some(if level < 1.0 / 3.0 {
+        0.0
+    } else if level > 2.0 / 3.0 {
+        1.0
+    } else {
+        0.5
+    })
+}
+
+/// Read the methylation states of an extracted methylome file, keyed by contig, position and strand. Sites without a status are called from their level, sites without either are skipped.
+fn read_states(path: &str) -> Result<HashMap<String, f64>> {
+    let mut states = HashMap::new();
+    for line in fs::read_to_string(path)?.lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 9 {
+            continue;
+        }
+        if let Some(state) = state(fields[7]).or_else(|| state_from_level(fields[8])) {
+            states.insert(fields[..3].join("\t"), state);
+        }
+    }
+    Ok(states)
+}
+
+/// Mean absolute difference of the states of the sites two samples have in common, `None` if they share no site
+pub fn divergence(a: &HashMap<String, f64>, b: &HashMap<String, f64>) -> Option<f64> {
+    let differences: Vec<f64> = a
+        .iter()
+        .filter_map(|(site, state)| b.get(site).map(|other| (state - other).abs()))
+        .collect();
+    if differences.is_empty() {
+        return None;
+    }
+    Some(differences.iter().sum::<f64>() / differences.len() as f64)
+}
+
+impl Pedigree {
+    /// Generations separating two nodes from their most recent common ancestor, `None` if they are not related
+    pub fn separation(&self, a: &str, b: &str) -> Option<(u32, u32)> {
+        let ancestors_a = self.ancestors(a);
+        let ancestors_b = self.ancestors(b);
+        let generation = |id: &str| self.nodes.iter().find(|n| n.id == id).map(|n| n.generation);
+        let common = ancestors_a
+            .iter()
+            .filter(|id| ancestors_b.contains(id))
+            .max_by_key(|id| generation(id))?;
+        let t0 = generation(common)?;
+        // Ancestors of an unchecked pedigree may be of a later generation
+        Some((
+            generation(a)?.checked_sub(t0)?,
+            generation(b)?.checked_sub(t0)?,
+        ))
+    }
+
+    /// The node itself and all its ancestors
+    fn ancestors<'a>(&'a self, id: &'a str) -> Vec<&'a str> {
+        let mut ancestors = vec![id];
+        let mut i = 0;
+        while i < ancestors.len() {
+            let child = ancestors[i];
+            for (from, to) in &self.edges {
+                if to == child && !ancestors.contains(&from.as_str()) {
+                    ancestors.push(from);
+                }
+            }
+            i += 1;
+        }
+        ancestors
+    }
+}
+
+/// Estimate the epimutation rates of every window directory created by the extraction, writing one row per window to `epimutation_rates.tsv` in the output directory.
+pub fn estimate_epimutation_rates(args: &Args, layout: &Layout, pedigree: &Pedigree) -> Result<()> {
+    let sequenced: Vec<&Node> = pedigree
+        .nodes
+        .iter()
+        .filter(|n| n.methylome.is_some())
+        .collect();
+    let pairs: Vec<(usize, usize, u32, u32)> = (0..sequenced.len())
+        .flat_map(|i| (i + 1..sequenced.len()).map(move |j| (i, j)))
+        .filter_map(|(i, j)| {
+            let (t1, t2) = pedigree.separation(&sequenced[i].id, &sequenced[j].id)?;
+            Some((i, j, t1, t2))
+        })
+        .collect();
+
+    let mut windows = Vec::new();
+    for context in args.contexts()? {
+        for region in layout.regions() {
+            let region = layout.name(region);
+            let region_dir = format!("{}/{}/{}", args.output_dir, context, region);
+            // Anchored profiles have negative window starts
+            let mut starts: Vec<i32> = fs::read_dir(&region_dir)?
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect();
+            starts.sort();
+            windows.extend(
+                starts
+                    .into_iter()
+                    .map(|start| (context, region.clone(), start)),
+            );
+        }
+    }
+
+    let rows: Vec<String> = windows
+        .par_iter()
+        .map(|(context, region, start)| -> Result<String> {
+            let window_dir = format!("{}/{}/{}/{}", args.output_dir, context, region, start);
+            let states = sequenced
+                .iter()
+                .map(|node| {
+                    let methylome = OsString::from(node.methylome.as_ref().unwrap());
+                    read_states(&format!("{}/{}", window_dir, output_name(&methylome)))
+                })
+                .collect::<Result<Vec<_>>>()?;
+
+            let mut counts = [0.0; GENOTYPES];
+            let mut sites = std::collections::HashSet::new();
+            for sample in &states {
+                for (site, state) in sample {
+                    counts[(state * 2.0) as usize] += 1.0;
+                    sites.insert(site);
+                }
+            }
+            let divergences: Vec<Divergence> = pairs
+                .iter()
+                .filter_map(|(i, j, t1, t2)| {
+                    Some(Divergence {
+                        t1: *t1,
+                        t2: *t2,
+                        d: divergence(&states[*i], &states[*j])?,
+                    })
+                })
+                .collect();
+
+            let prefix = format!(
+                "{}\t{}\t{}\t{}\t{}",
+                context,
+                region,
+                start,
+                divergences.len(),
+                sites.len()
+            );
+            // Four parameters need at least as many observations
+            if divergences.len() < 4 {
+                return Ok(format!("{prefix}\tNA\tNA\tNA\tNA\tNA\n"));
+            }
+            let total: f64 = counts.iter().sum();
+            let model = Model {
+                p0: counts.map(|c| c / total),
+            };
+            let estimate = model.fit(&divergences);
+            Ok(format!(
+                "{}\t{:e}\t{:e}\t{:.4}\t{:.4}\t{:e}\n",
+                prefix,
+                estimate.alpha,
+                estimate.beta,
+                estimate.weight,
+                estimate.intercept,
+                estimate.rss
+            ))
+        })
+        .collect::<Result<_>>()?;
+
+    fs::write(
+        format!("{}/epimutation_rates.tsv", args.output_dir),
+        String::from(EPIMUTATION_HEADER) + &rows.concat(),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn pedigree() -> Pedigree {
+        let node = |id: &str, generation: u32| Node {
+            id: id.to_owned(),
+            generation,
+            lineage: id[id.len() - 1..].to_owned(),
+            methylome: Some(format!("{id}.txt")),
+        };
+        Pedigree {
+            nodes: vec![
+                node("0_0", 0),
+                node("1_1", 1),
+                node("2_1", 2),
+                node("1_2", 1),
+                node("3_2", 3),
+            ],
+            edges: vec![
+                (String::from("0_0"), String::from("1_1")),
+                (String::from("1_1"), String::from("2_1")),
+                (String::from("0_0"), String::from("1_2")),
+                (String::from("1_2"), String::from("3_2")),
+            ],
+        }
+    }
+
+    #[test]
+    fn separation_from_common_ancestor() {
+        let pedigree = pedigree();
+        assert_eq!(pedigree.separation("2_1", "3_2"), Some((2, 3)));
+        assert_eq!(pedigree.separation("1_1", "2_1"), Some((0, 1)));
+        assert_eq!(pedigree.separation("0_0", "0_0"), Some((0, 0)));
+
+        // An edge to an earlier generation does not underflow
+        let mut pedigree = pedigree;
+        pedigree.nodes[0].generation = 4;
+        assert_eq!(pedigree.separation("2_1", "3_2"), None);
+    }
+
+    #[test]
+    fn read_states_of_window_file() {
+        let path = std::env::temp_dir().join("extractor_states.txt");
+        fs::write(
+            &path,
+            "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl
+1\t10\t+\tCG\t6\t8\t0.9999\tI\t0.7500
+1\t20\t+\tCG\t6\t8\tNA\tNA\t0.7500
+1\t30\t+\tCG\t1\t8\tNA\tNA\t0.1250
+1\t40\t+\tCG\t0\t0\tNA\tNA\tNA
+",
+        )
+        .unwrap();
+        let states = read_states(path.to_str().unwrap()).unwrap();
+        // The status is taken over, sites of Bismark files are called from their level
+        assert_eq!(states["1\t10\t+"], 0.5);
+        assert_eq!(states["1\t20\t+"], 1.0);
+        assert_eq!(states["1\t30\t+"], 0.0);
+        assert_eq!(states.len(), 3);
+        fs::remove_file(&path).unwrap();
+        assert!(read_states(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn divergence_of_shared_sites() {
+        let sample = |states: &[(&str, f64)]| -> HashMap<String, f64> {
+            states.iter().map(|(k, v)| (k.to_string(), *v)).collect()
+        };
+        let a = sample(&[("1\t10\t+", 1.0), ("1\t20\t+", 0.0), ("1\t30\t+", 0.5)]);
+        let b = sample(&[("1\t10\t+", 0.0), ("1\t20\t+", 0.0), ("1\t40\t+", 1.0)]);
+        assert_eq!(divergence(&a, &b), Some(0.5));
+        assert_eq!(divergence(&a, &HashMap::new()), None);
+    }
+
+    #[test]
+    fn no_divergence_without_generations() {
+        let model = Model {
+            p0: [0.5, 0.0, 0.5],
+        };
+        let estimate = Estimate {
+            alpha: 1e-4,
+            beta: 1e-3,
+            weight: 0.5,
+            intercept: 0.01,
+            rss: 0.0,
+        };
+        assert!((model.divergence(&estimate, 0, 0) - 0.01).abs() < 1e-12);
+        assert!(model.divergence(&estimate, 5, 5) > model.divergence(&estimate, 1, 1));
+    }
+
+    #[test]
+    fn minimize_quadratic() {
+        let (x, fx) = nelder_mead(
+            |x| (x[0] - 3.0).powi(2) + (x[1] + 1.0).powi(2),
+            &[0.0, 0.0],
+            1.0,
+            1000,
+        );
+        assert!((x[0] - 3.0).abs() < 1e-4);
+        assert!((x[1] + 1.0).abs() < 1e-4);
+        assert!(fx < 1e-8);
+    }
+
+    #[test]
+    fn fit_simulated_divergences() {
+        let model = Model {
+            p0: [0.6, 0.1, 0.3],
+        };
+        let truth = Estimate {
+            alpha: 1e-3,
+            beta: 5e-3,
+            weight: 0.5,
+            intercept: 0.02,
+            rss: 0.0,
+        };
+        let divergences: Vec<Divergence> = (0..8)
+            .flat_map(|t1| (t1..8).map(move |t2| (t1, t2)))
+            .map(|(t1, t2)| Divergence {
+                t1,
+                t2,
+                d: model.divergence(&truth, t1, t2),
+            })
+            .collect();
+        let estimate = model.fit(&divergences);
+        assert!(estimate.rss < 1e-8, "{estimate:?}");
+        for (t1, t2) in [(1, 1), (5, 7), (7, 7)] {
+            let expected = model.divergence(&truth, t1, t2);
+            assert!((model.divergence(&estimate, t1, t2) - expected).abs() < 1e-4);
+        }
+    }
+}
@@ -16,6 +16,47 @@ pub enum Error {
     #[error("Unable to extract CG site from line")]
     CGSite,
 
+    #[error("Unknown methylation context: {0}")]
+    Context(String),
+
+    #[error("Unknown methylation status: {0}")]
+    Status(String),
+
+    #[error("Unknown contig {0}")]
+    UnknownContig(String),
+
+    #[error("Unable to detect the format of methylome file {0}, please specify it with --format")]
+    UnknownFormat(String),
+
+    #[error("Unable to detect the format of annotation file {0}, please specify it with --annotation-format")]
+    UnknownAnnotationFormat(String),
+
+    #[error(
+        "No genes found in the annotation file {0}. Are the --feature-types present in the file?"
+    )]
+    NoGenes(String),
+
+    #[error("Invalid window layout: {0}")]
+    Layout(String),
+
+    #[error("Unable to use the output directory: {0}")]
+    OutputDir(String),
+
+    #[error("Invalid feature set: {0}")]
+    FeatureSet(String),
+
+    #[error("Invalid pedigree: {0}")]
+    Pedigree(String),
+
+    #[error("Invalid methylome index: {0}")]
+    Index(String),
+
+    #[error("Invalid methylome cache: {0}")]
+    Cache(String),
+
+    #[error("Unable to start the worker threads: {0}")]
+    ThreadPool(#[from] rayon::ThreadPoolBuildError),
+
     #[error("Unable to convert: Are you passing a valid number? {0}")]
     NumberConversion(#[from] std::num::ParseIntError),
 }
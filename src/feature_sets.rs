@@ -0,0 +1,159 @@
+use std::str::FromStr;
+
+use crate::*;
+
+/// A labelled annotation file, given as `label=path` with --features
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeatureFile {
+    pub label: String,
+    pub path: String,
+}
+
+impl FromStr for FeatureFile {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((label, path))
+                if !label.is_empty() && !label.contains('/') && !path.is_empty() =>
+            {
+                Ok(FeatureFile {
+                    label: label.to_owned(),
+                    path: path.to_owned(),
+                })
+            }
+            _ => Err(Error::FeatureSet(format!(
+                "expected label=path with a label that is a valid directory name, got {s}"
+            ))),
+        }
+    }
+}
+
+/// Features of one annotation file of a run, e.g. gbM genes or transposable elements, with the arguments to process them.
+/// Every feature set has its own window tree, summary and profile, but all sets are filled in a single pass over each methylome file.
+#[derive(Clone)]
+pub struct FeatureSet {
+    /// Label of a set given with --features, `None` for the annotation of --genome in a run without --features
+    pub label: Option<String>,
+    /// Arguments of the run, with the annotation file and output directory of the set
+    pub args: Args,
+    pub genes: Vec<Gene>,
+    /// Genes structured first by contig, then by + and - strand => [Contig_1(+ Strand, - Strand), Contig_2(+,-), ..]
+    pub genome: Vec<GenesByStrand>,
+    pub max_gene_length: i32,
+    pub layout: Layout,
+}
+
+impl FeatureSet {
+    /// Load the features of the annotation file of the arguments. Their contigs are added to the dictionary, which is shared by all sets of a run.
+    pub fn load(label: Option<String>, args: Args, contigs: &mut Contigs) -> Result<Self> {
+        let mut genes = load_genes(&args, contigs)?;
+        if let Some(truncation) = args.truncate_flanks {
+            truncate_flanks(&mut genes, truncation);
+        }
+        FeatureSet::new(label, args, genes, contigs.len())
+    }
+
+    /// Structure the genes for the lookup of sites and derive the window layout from the longest gene
+    pub fn new(
+        label: Option<String>,
+        args: Args,
+        genes: Vec<Gene>,
+        contig_count: usize,
+    ) -> Result<Self> {
+        let mut genome: Vec<GenesByStrand> = vec![GenesByStrand::new(); contig_count];
+        // Put genes into their correct bucket
+        let mut gene_length_sum = 0;
+        let mut sense_gene_count = 0;
+        genes.iter().for_each(|g| {
+            if g.strand == Strand::Sense {
+                sense_gene_count += 1;
+            }
+            gene_length_sum += g.end - g.start;
+            genome[g.chromosome].insert(g.to_owned());
+        });
+        // Sort genes by start bp (propably already the case), needed for binary search
+        genome.iter_mut().for_each(GenesByStrand::sort);
+        let average_gene_length = gene_length_sum / genes.len().max(1) as i32;
+        println!(
+            "{}: average gene length: {} bp, {} genes, of which {} are on the sense strand and {} on the antisense strand",
+            args.genome,
+            average_gene_length,
+            genes.len(),
+            sense_gene_count,
+            genes.len() - sense_gene_count
+        );
+
+        // Determine the maximum gene length by iterating over all genes
+        let mut max_gene_length: i32 = 100;
+        for gene in &genes {
+            let length = gene.end - gene.start;
+            if length > max_gene_length {
+                max_gene_length = length
+            }
+        }
+        let layout = args.layout(max_gene_length);
+        layout.check()?;
+        if layout.gene.absolute {
+            println!("The maximum gene length is {} bp", max_gene_length);
+        }
+
+        Ok(FeatureSet {
+            label,
+            args,
+            genes,
+            genome,
+            max_gene_length,
+            layout,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_feature_file() {
+        assert_eq!(
+            "te=TEs.gff".parse::<FeatureFile>().unwrap(),
+            FeatureFile {
+                label: String::from("te"),
+                path: String::from("TEs.gff")
+            }
+        );
+        assert!("TEs.gff".parse::<FeatureFile>().is_err());
+        assert!("=TEs.gff".parse::<FeatureFile>().is_err());
+        assert!("a/b=TEs.gff".parse::<FeatureFile>().is_err());
+    }
+
+    #[test]
+    fn feature_sets_of_arguments() {
+        let args = Args {
+            genome: String::from("gbM.bed"),
+            output_dir: String::from("windows"),
+            ..Default::default()
+        };
+        let sets = args.feature_sets().unwrap();
+        assert_eq!(sets.len(), 1);
+        assert_eq!(sets[0].0, None);
+        assert_eq!(sets[0].1.output_dir, "windows");
+
+        let args = Args {
+            features: vec!["te=TEs.gff".parse().unwrap()],
+            ..args
+        };
+        let sets = args.feature_sets().unwrap();
+        assert_eq!(sets.len(), 2);
+        assert_eq!(sets[0].0.as_deref(), Some("genes"));
+        assert_eq!(sets[0].1.genome, "gbM.bed");
+        assert_eq!(sets[1].1.genome, "TEs.gff");
+        assert_eq!(sets[1].1.output_dir, "windows/te");
+
+        let duplicate = Args {
+            features: vec!["genes=TEs.gff".parse().unwrap()],
+            ..args
+        };
+        assert!(duplicate.feature_sets().is_err());
+    }
+}
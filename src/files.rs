@@ -1,13 +1,92 @@
+use flate2::read::MultiGzDecoder;
+
 use crate::*;
 
-pub fn open_file(path: &PathBuf, filename: &OsString) -> Result<File> {
+/// Magic bytes at the start of every gzip member. BGZF (bgzip) files are a series of gzip members, so they share the same signature.
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// File name suffixes of compressed methylome files, stripped from the names of the extracted output files.
+const COMPRESSION_SUFFIXES: [&str; 3] = [".gz", ".bgz", ".bgzf"];
+
+/// File name suffixes of tabix and CSI indices and of the binary caches, which are not methylome files themselves
+const INDEX_SUFFIXES: [&str; 3] = [".tbi", ".csi", CACHE_SUFFIX];
+
+pub type MethylomeReader = Box<dyn BufRead + Send>;
+
+/// Size of the smallest chunk of a plain methylome file that is read by a thread of its own. Smaller files are not split.
+const MIN_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Open a methylome file for line-by-line reading.
+/// Gzip and bgzip compressed files are detected by their magic bytes (not by their extension) and decompressed while streaming.
+/// As bgzip files consist of many concatenated gzip members, all members are decoded, not only the first one.
+pub fn open_file(path: &PathBuf, filename: &OsString) -> Result<MethylomeReader> {
     let file = File::open(path).map_err(|_| {
         Error::File(
             String::from("Could not find methylome file with name "),
             String::from(filename.to_str().unwrap()),
         )
     })?;
-    Ok(file)
+    let mut reader = io::BufReader::new(file);
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(io::BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Open a methylome file as up to `count` chunks of whole lines that can be read in parallel, in the order of the file.
+/// Plain files are split into byte ranges of at least `MIN_CHUNK_SIZE` bytes. Compressed files can not be read from an arbitrary offset and are read as a single chunk.
+pub fn open_chunks(
+    path: &PathBuf,
+    filename: &OsString,
+    count: usize,
+) -> Result<Vec<MethylomeReader>> {
+    let length = fs::metadata(path)?.len();
+    let count = count.min((length / MIN_CHUNK_SIZE) as usize);
+    let mut file = File::open(path)?;
+    let mut magic = [0; 2];
+    let compressed = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    if count < 2 || compressed {
+        return Ok(vec![open_file(path, filename)?]);
+    }
+    line_boundaries(&mut file, length, count)?
+        .windows(2)
+        .map(|range| -> Result<MethylomeReader> {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(range[0]))?;
+            Ok(Box::new(io::BufReader::new(file.take(range[1] - range[0]))))
+        })
+        .collect()
+}
+
+/// Offsets splitting a file into `count` parts of about the same size, each moved forward to the start of a line. Starts with 0 and ends with the length of the file.
+fn line_boundaries(file: &mut File, length: u64, count: usize) -> Result<Vec<u64>> {
+    let mut boundaries = vec![0];
+    for k in 1..count as u64 {
+        let offset = length * k / count as u64;
+        if offset <= *boundaries.last().unwrap() {
+            continue;
+        }
+        // Reading from the byte before the offset, a line starting exactly at the offset is kept whole
+        file.seek(SeekFrom::Start(offset - 1))?;
+        let read = io::BufReader::new(&mut *file).read_until(b'\n', &mut Vec::new())?;
+        let boundary = offset - 1 + read as u64;
+        if boundary < length {
+            boundaries.push(boundary);
+        }
+    }
+    boundaries.push(length);
+    Ok(boundaries)
+}
+
+/// Name of the file the extracted sites of a methylome are written to, which is the methylome file name without a compression suffix.
+pub fn output_name(filename: &OsString) -> String {
+    let name = filename.to_str().unwrap();
+    COMPRESSION_SUFFIXES
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .unwrap_or(name)
+        .to_owned()
 }
 
 pub fn lines_from_file(filename: &str) -> Result<io::Lines<io::BufReader<File>>> {
@@ -27,8 +106,99 @@ pub fn load_methylome(methylome: &str) -> Result<Vec<(PathBuf, OsString)>> {
             String::from(methylome),
         )
     })?;
-    let methylome_files = methylome_dir
+    let mut methylome_files: Vec<(PathBuf, OsString)> = methylome_dir
         .map(|f| (f.as_ref().unwrap().path(), f.unwrap().file_name()))
+        // Indices and caches of the methylomes lie next to them
+        .filter(|(_, name)| {
+            let name = name.to_string_lossy();
+            !INDEX_SUFFIXES.iter().any(|suffix| name.ends_with(suffix))
+        })
         .collect();
+    // Sorted, so that tables covering all methylome files are written in a stable order
+    methylome_files.sort();
     Ok(methylome_files)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const LINES: [&str; 3] = [
+        "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl",
+        "1\t23151\t+\tCG\t0\t8\t0.9999\tU\t0.0025",
+        "1\t23152\t-\tCG\t6\t8\t0.9999\tM\t0.7500",
+    ];
+
+    fn gzip(content: &str) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_back(name: &str, content: &[u8]) -> Vec<String> {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, content).unwrap();
+        let lines = open_file(&path, &OsString::from(name))
+            .unwrap()
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+        fs::remove_file(path).unwrap();
+        lines
+    }
+
+    #[test]
+    fn open_plain_file() {
+        let content = LINES.join("\n");
+        let lines = read_back("extractor_plain.txt", content.as_bytes());
+        assert_eq!(lines, LINES);
+    }
+
+    #[test]
+    fn open_gzip_file() {
+        let content = gzip(&LINES.join("\n"));
+        let lines = read_back("extractor_gzip.txt.gz", &content);
+        assert_eq!(lines, LINES);
+    }
+
+    #[test]
+    fn open_multi_member_gzip_file() {
+        // bgzip writes one gzip member per block, which is mimicked here by concatenating one member per line
+        let content: Vec<u8> = LINES
+            .iter()
+            .flat_map(|line| gzip(&format!("{line}\n")))
+            .collect();
+        let lines = read_back("extractor_multi_member.txt", &content);
+        assert_eq!(lines, LINES);
+    }
+
+    #[test]
+    fn split_at_line_starts() {
+        let content = LINES.join("\n") + "\n";
+        let path = std::env::temp_dir().join("extractor_chunks.txt");
+        fs::write(&path, &content).unwrap();
+        let length = content.len() as u64;
+        for count in 1..6 {
+            let boundaries =
+                line_boundaries(&mut File::open(&path).unwrap(), length, count).unwrap();
+            assert_eq!(boundaries.first(), Some(&0));
+            assert_eq!(boundaries.last(), Some(&length));
+            assert!(boundaries.len() <= LINES.len() + 1);
+            for boundary in &boundaries[1..boundaries.len() - 1] {
+                assert_eq!(content.as_bytes()[*boundary as usize - 1], b'\n');
+            }
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn strip_compression_suffix() {
+        assert_eq!(output_name(&OsString::from("G0.txt.gz")), "G0.txt");
+        assert_eq!(output_name(&OsString::from("G0.txt.bgz")), "G0.txt");
+        assert_eq!(output_name(&OsString::from("G0.txt")), "G0.txt");
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+
+use clap::ValueEnum;
+
+use crate::*;
+
+/// Header of the table of effective flank lengths, one row per gene
+pub const FLANKS_HEADER: &str = "gene\tcontig\tstart\tend\tstrand\tupstream\tdownstream\n";
+
+/// Which neighbouring genes truncate the flanks of a gene
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Truncation {
+    /// Genes on either strand
+    #[default]
+    AnyStrand,
+    /// Only genes on the same strand
+    SameStrand,
+}
+
+/// Upstream and downstream flank lengths of a gene up to the neighbouring genes, `i32::MAX` if there is no neighbour. The effective flanks are at most the region lengths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Flanks {
+    pub upstream: i32,
+    pub downstream: i32,
+}
+
+/// Truncate the flanks of every gene at the nearest neighbouring gene, i.e. the closest gene ending before its start and the closest gene starting after its end.
+/// Genes overlapping a gene are not its neighbours, so overlapping and nested genes keep their flanks, which reach into the body of the other gene.
+pub fn truncate_flanks(genes: &mut [Gene], truncation: Truncation) {
+    let key = |g: &Gene| match truncation {
+        Truncation::AnyStrand => (g.chromosome, None),
+        Truncation::SameStrand => (g.chromosome, Some(g.strand.clone())),
+    };
+    // Sorted starts and ends of the neighbours of each contig (and strand)
+    let mut neighbours: HashMap<_, (Vec<i32>, Vec<i32>)> = HashMap::new();
+    for gene in genes.iter() {
+        let (starts, ends) = neighbours.entry(key(gene)).or_default();
+        starts.push(gene.start);
+        ends.push(gene.end);
+    }
+    for (starts, ends) in neighbours.values_mut() {
+        starts.sort();
+        ends.sort();
+    }
+
+    for gene in genes.iter_mut() {
+        let (starts, ends) = &neighbours[&key(gene)];
+        // Bases between the gene and the closest gene ending before its start and starting after its end
+        let left = match ends.partition_point(|e| *e < gene.start) {
+            0 => i32::MAX,
+            i => gene.start - ends[i - 1] - 1,
+        };
+        let right = match starts.partition_point(|s| *s <= gene.end) {
+            i if i == starts.len() => i32::MAX,
+            i => starts[i] - gene.end - 1,
+        };
+        let (upstream, downstream) = match gene.strand {
+            Strand::Sense => (left, right),
+            Strand::Antisense => (right, left),
+        };
+        gene.flanks = Some(Flanks {
+            upstream,
+            downstream,
+        });
+    }
+}
+
+/// Table of the effective flank lengths of all genes
+pub fn flank_table(genes: &[Gene], contigs: &Contigs, layout: &Layout) -> String {
+    let mut table = String::from(FLANKS_HEADER);
+    for gene in genes {
+        table += &format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            gene.name,
+            contigs.name(gene.chromosome),
+            gene.start,
+            gene.end,
+            gene.strand,
+            gene.upstream(layout.upstream.length),
+            gene.downstream(layout.downstream.length)
+        );
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gene(start: i32, end: i32, strand: Strand) -> Gene {
+        Gene {
+            chromosome: 0,
+            start,
+            end,
+            name: String::new(),
+            strand,
+            flanks: None,
+            transcript: None,
+        }
+    }
+
+    #[test]
+    fn truncate_at_neighbours() {
+        let mut genes = vec![
+            gene(1000, 2000, Strand::Sense),
+            gene(2500, 3000, Strand::Antisense),
+            gene(2600, 2700, Strand::Sense),
+            gene(6000, 7000, Strand::Sense),
+        ];
+        truncate_flanks(&mut genes, Truncation::AnyStrand);
+        let flanks = |g: &Gene| (g.upstream(2048), g.downstream(2048));
+        assert_eq!(flanks(&genes[0]), (2048, 499));
+        // Upstream of an antisense gene is towards higher coordinates, the nested gene does not count
+        assert_eq!(flanks(&genes[1]), (2048, 499));
+        assert_eq!(flanks(&genes[2]), (599, 2048));
+        assert_eq!(flanks(&genes[3]), (2048, 2048));
+
+        truncate_flanks(&mut genes, Truncation::SameStrand);
+        assert_eq!(flanks(&genes[0]), (2048, 599));
+        assert_eq!(flanks(&genes[1]), (2048, 2048));
+        assert_eq!(flanks(&genes[2]), (599, 2048));
+        // A shorter region length still applies
+        assert_eq!(genes[0].downstream(100), 100);
+    }
+
+    #[test]
+    fn overlapping_genes_keep_flanks() {
+        let mut genes = vec![
+            gene(1000, 2000, Strand::Sense),
+            gene(1500, 2500, Strand::Sense),
+            gene(1600, 1700, Strand::Antisense),
+        ];
+        truncate_flanks(&mut genes, Truncation::AnyStrand);
+        let flanks = |g: &Gene| (g.upstream(2048), g.downstream(2048));
+        // The downstream flank of the first gene reaches into the body of the second one and vice versa
+        assert_eq!(flanks(&genes[0]), (2048, 2048));
+        assert_eq!(flanks(&genes[1]), (2048, 2048));
+        // The flanks of the nested gene reach into the bodies of both enclosing genes
+        assert_eq!(flanks(&genes[2]), (2048, 2048));
+    }
+}
@@ -0,0 +1,163 @@
+use clap::ValueEnum;
+
+use crate::*;
+
+/// Layout of the methylome files. Regardless of the input format, the extracted sites are written in the methimpute layout.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MethylomeFormat {
+    /// Detect the format from the first line of each methylome file that is a site or the methimpute header
+    #[default]
+    Auto,
+    /// methimpute / MethylStar output: seqnames, start, strand, context, counts.methylated, counts.total, posteriorMax, status, rc.meth.lvl
+    Methimpute,
+    /// Bismark coverage file (.cov): chr, start, end, %meth, meth, unmeth
+    Coverage,
+    /// Bismark cytosine report (CX_report): chr, pos, strand, meth, unmeth, context, trinucleotide
+    CxReport,
+}
+
+impl MethylomeFormat {
+    /// Detect the format of a methylome file from a line, which is either the methimpute header or a site. Other lines, e.g. the `track` line of a Bismark file, yield `None`.
+    pub fn detect(line: &str) -> Option<Self> {
+        let is_strand = |s: &&str| *s == "+" || *s == "-";
+        let is_context = |s: &&str| s.parse::<Context>().is_ok();
+        let is_number = |s: &&str| s.parse::<u32>().is_ok();
+
+        let fields: Vec<&str> = line.split('\t').collect();
+        match fields.as_slice() {
+            ["seqnames", "start", "strand", "context", ..] => Some(MethylomeFormat::Methimpute),
+            [_, start, strand, context, _, _, _, _, _]
+                if is_number(start) && is_strand(strand) && is_context(context) =>
+            {
+                Some(MethylomeFormat::Methimpute)
+            }
+            [_, pos, strand, _, _, context, _]
+                if is_number(pos) && is_strand(strand) && is_context(context) =>
+            {
+                Some(MethylomeFormat::CxReport)
+            }
+            [_, start, end, _, methylated, unmethylated]
+                if [start, end, methylated, unmethylated]
+                    .into_iter()
+                    .all(is_number) =>
+            {
+                Some(MethylomeFormat::Coverage)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn parse_line(
+        &self,
+        line: &str,
+        contigs: &Contigs,
+        invert_strand: bool,
+    ) -> Result<MethylationSite> {
+        match self {
+            // The format has to be detected before lines can be parsed
+            MethylomeFormat::Auto => Err(Error::CGSite),
+            MethylomeFormat::Methimpute => {
+                MethylationSite::from_methylome_file_line(line, contigs, invert_strand)
+            }
+            MethylomeFormat::Coverage => {
+                MethylationSite::from_coverage_file_line(line, contigs, invert_strand)
+            }
+            MethylomeFormat::CxReport => {
+                MethylationSite::from_cx_report_file_line(line, contigs, invert_strand)
+            }
+        }
+    }
+
+    /// Whether the sites of this format know their strand. Sites of unstranded formats are matched against genes on both strands.
+    pub fn is_stranded(&self) -> bool {
+        *self != MethylomeFormat::Coverage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MethylomeFormat;
+    use crate::*;
+
+    const METHIMPUTE_HEADER: &str = "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl";
+    const METHIMPUTE: &str = "1\t23151\t+\tCG\t0\t8\t0.9999\tU\t0.0025";
+    const COVERAGE: &str = "1\t23151\t23151\t75\t6\t2";
+    const CX_REPORT: &str = "1\t23151\t-\t6\t2\tCHG\tCAG";
+
+    fn contigs() -> Contigs {
+        let mut contigs = Contigs::new();
+        contigs.intern("1");
+        contigs
+    }
+
+    #[test]
+    fn detect_format() {
+        assert_eq!(
+            MethylomeFormat::detect(METHIMPUTE_HEADER),
+            Some(MethylomeFormat::Methimpute)
+        );
+        assert_eq!(
+            MethylomeFormat::detect(METHIMPUTE),
+            Some(MethylomeFormat::Methimpute)
+        );
+        assert_eq!(
+            MethylomeFormat::detect(COVERAGE),
+            Some(MethylomeFormat::Coverage)
+        );
+        assert_eq!(
+            MethylomeFormat::detect(CX_REPORT),
+            Some(MethylomeFormat::CxReport)
+        );
+        assert_eq!(MethylomeFormat::detect("track type=bedGraph"), None);
+    }
+
+    #[test]
+    fn parse_coverage() {
+        let site = MethylomeFormat::Coverage
+            .parse_line(COVERAGE, &contigs(), false)
+            .unwrap();
+        assert_eq!(site.location, 23151);
+        assert_eq!(site.strand, Strand::Sense);
+        assert_eq!(site.context, Context::CG);
+        assert_eq!(site.methylated, 6);
+        assert_eq!(site.total, 8);
+        assert_eq!(site.original, "1\t23151\t+\tCG\t6\t8\tNA\tNA\t0.7500");
+        assert_eq!(
+            site.mirrored().original,
+            "1\t23151\t-\tCG\t6\t8\tNA\tNA\t0.7500"
+        );
+    }
+
+    #[test]
+    fn parse_cx_report() {
+        let site = MethylomeFormat::CxReport
+            .parse_line(CX_REPORT, &contigs(), false)
+            .unwrap();
+        assert_eq!(site.strand, Strand::Antisense);
+        assert_eq!(site.context, Context::CHG);
+        assert_eq!(site.total, 8);
+        assert_eq!(site.original, "1\t23151\t-\tCHG\t6\t8\tNA\tNA\t0.7500");
+    }
+
+    #[test]
+    fn parse_uncovered_cx_report() {
+        let site = MethylomeFormat::CxReport
+            .parse_line("1\t23151\t+\t0\t0\tCG\tCGA", &contigs(), false)
+            .unwrap();
+        assert!(site.level.is_nan());
+        assert_eq!(site.original, "1\t23151\t+\tCG\t0\t0\tNA\tNA\tNA");
+    }
+
+    #[test]
+    fn parse_methimpute() {
+        let site = MethylomeFormat::Methimpute
+            .parse_line(METHIMPUTE, &contigs(), false)
+            .unwrap();
+        assert_eq!(site.status, Some(Status::Unmethylated));
+        assert_eq!(site.posterior, Some(0.9999));
+        assert_eq!(site.original, METHIMPUTE);
+        assert!(MethylomeFormat::Methimpute
+            .parse_line(METHIMPUTE_HEADER, &contigs(), false)
+            .is_err());
+    }
+}
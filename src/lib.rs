@@ -1,129 +1,288 @@
-use crate::{arguments::Args, error::Error};
+use crate::{
+    arguments::{Args, IndexArgs},
+    error::Error,
+};
 
+use annotation::*;
+use cache::*;
+use contigs::*;
+use epimutation::*;
+use feature_sets::*;
 use files::*;
+use flanks::*;
+use formats::*;
+use matrix::*;
 use methylation_site::*;
+use pedigree::*;
 use rayon::prelude::*;
-use setup::set_up_output_dir;
+use setup::{plan, set_up_output_dir};
 use std::{
     ffi::OsString,
     fs::{self, File},
-    io::{self, BufRead},
-    path::PathBuf,
+    io::{self, BufRead, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
 };
 use structs::*;
+use summary::*;
+use sweep::*;
+use tabix::*;
+use tiles::*;
+use transcripts::*;
 use windows::*;
 
+mod annotation;
 pub mod arguments;
+mod cache;
+mod contigs;
+mod epimutation;
 mod error;
+mod feature_sets;
 mod files;
+mod flanks;
+mod formats;
+mod matrix;
 mod methylation_site;
+mod overlap;
+mod pedigree;
 mod setup;
 mod structs;
+mod summary;
+mod sweep;
+mod tabix;
+mod tiles;
+mod transcripts;
 mod windows;
 
-pub fn extract(args: Args) -> Result<()> {
+/// Fixtures shared with the integration tests
+#[cfg(test)]
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+/// Rows of the tables of one feature set for one methylome file
+#[derive(Default)]
+struct SetRows {
+    summary: String,
+    profile: String,
+    features: String,
+    genes: String,
+}
+
+/// Write a binary cache next to every methylome file of a directory, see `write_cache`
+pub fn index(args: IndexArgs) -> Result<()> {
     let start = std::time::Instant::now();
-    let mut args = args;
+    load_methylome(&args.methylome)?
+        .par_iter()
+        .map(|(path, filename)| {
+            let sites = write_cache(path, filename, args.format)?;
+            println!("Cached {} sites of {}", sites, filename.to_string_lossy());
+            Ok(())
+        })
+        .collect::<Result<()>>()?;
+    println!("Done in: {:?}", start.elapsed());
+    Ok(())
+}
 
-    // Adj ust window_step to default value
-    if args.window_step == 0 {
-        args.window_step = args.window_size;
+/// Open a methylome file as chunks that are processed in parallel. The sites are read from the binary cache of the file if it is up to date and of the given format,
+/// only within the regions of the features if the file has an index and `regions` are given, or else from byte ranges of the text file.
+fn open_methylome(
+    path: &PathBuf,
+    filename: &OsString,
+    chunks: usize,
+    regions: Option<&[Vec<(i32, i32)>]>,
+    contigs: &Contigs,
+    format: MethylomeFormat,
+) -> Result<Vec<Methylome>> {
+    if let Some(cached) = open_cache(path, chunks, format)? {
+        println!("Reading {} from its cache", filename.to_string_lossy());
+        return Ok(cached.into_iter().map(Methylome::Cached).collect());
+    }
+    if let Some(regions) = regions {
+        if let Some(reader) = open_indexed(path, regions, contigs)? {
+            println!(
+                "Reading {} only within the regions of the features, using its index",
+                filename.to_string_lossy()
+            );
+            return Ok(vec![Methylome::Text(reader)]);
+        }
     }
+    Ok(open_chunks(path, filename, chunks)?
+        .into_iter()
+        .map(Methylome::Text)
+        .collect())
+}
+
+pub fn extract(args: Args) -> Result<()> {
+    let start = std::time::Instant::now();
 
     let methylome_files = load_methylome(&args.methylome)?;
-    let annotation_lines = lines_from_file(&args.genome)?;
+    let mut contigs = match &args.contig_aliases {
+        Some(path) => Contigs::from_alias_file(path)?,
+        None => Contigs::new(),
+    };
+    let tiling = match &args.tiles {
+        Some(path) => Some(Tiling::from_sizes_file(
+            path,
+            args.tile_size,
+            args.tile_step,
+            &mut contigs,
+        )?),
+        None => None,
+    };
+    let sets = args
+        .feature_sets()?
+        .into_iter()
+        .map(|(label, set_args)| FeatureSet::load(label, set_args, &mut contigs))
+        .collect::<Result<Vec<FeatureSet>>>()?;
+
+    if args.epimutation && args.pedigree.is_none() {
+        return Err(Error::Pedigree(String::from(
+            "estimating epimutation rates requires --pedigree",
+        )));
+    }
+    let pedigree = match &args.pedigree {
+        Some(path) => {
+            let pedigree = Pedigree::from_file(path)?;
+            pedigree.validate(&methylome_files)?;
+            Some(pedigree)
+        }
+        None => None,
+    };
 
-    let mut genes: Vec<Gene> = Vec::new();
+    if args.dry_run {
+        print!("{}", plan(&args, &sets, tiling.as_ref(), &methylome_files)?);
+        return Ok(());
+    }
 
-    // Parse annotation file to extract genes
-    for line in annotation_lines {
-        let line = line?;
-        let gene = Gene::from_annotation_file_line(&line, args.invert);
-        if let Some(gene) = gene {
-            genes.push(gene)
+    set_up_output_dir(&args, &sets, pedigree.as_ref())?;
+    if args.truncate_flanks.is_some() {
+        for set in &sets {
+            fs::write(
+                format!("{}/flanks.tsv", &set.args.output_dir),
+                flank_table(&set.genes, &contigs, &set.layout),
+            )?;
         }
     }
 
-    // number of different chromosomes assuming they are named from 1 to highest
-    let chromosome_count = genes
-        .iter()
-        .max_by_key(|g| g.chromosome)
-        .unwrap()
-        .chromosome;
+    // Methylome files with a tabix or CSI index are only read within the regions of the features, unless the whole genome is tiled
+    let regions = match &tiling {
+        Some(_) => None,
+        None => Some(feature_regions(&sets, contigs.len())),
+    };
 
-    genes.sort_by_key(|g| g.start); // Sort genes by start bp (propably already the case), needed for binary search
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build()?;
+    // Files are split into chunks if there are fewer files than threads
+    let chunks = (pool.current_num_threads() / methylome_files.len().max(1)).max(1);
 
-    // Structure genes first by chromosome, then by + and - strand => [Chromosome_1(+ Strand, - Strand), Chromosome_2(+,-), ..]
-    let mut structured_genes: Vec<GenesByStrand> = vec![
-        GenesByStrand {
-            sense: Vec::new(),
-            antisense: Vec::new()
+    // Rows of the tables of every methylome file for each feature set, and the rows of the tile table
+    let tables: Vec<(Vec<SetRows>, String)> = pool.install(|| {
+        methylome_files
+            .par_iter()
+            .map(|(path, filename)| -> Result<_> {
+                let methylomes = open_methylome(
+                    path,
+                    filename,
+                    chunks,
+                    regions.as_deref(),
+                    &contigs,
+                    args.format,
+                )?;
+                let chunks = methylomes
+                    .into_par_iter()
+                    .enumerate()
+                    .map(|(chunk, methylome)| {
+                        extract_windows(
+                            methylome,
+                            &sets,
+                            tiling.as_ref(),
+                            &contigs,
+                            filename,
+                            chunk,
+                            &args,
+                        )
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                let (windows_by_set, tiles, counts) = merge_chunks(chunks)?;
+                counts.log(filename, &sets, &args);
+                let sample = output_name(filename);
+                let mut rows = Vec::new();
+                for (set, windows_by_context) in sets.iter().zip(windows_by_set) {
+                    let mut set_rows = SetRows::default();
+                    for (context, mut windows) in windows_by_context {
+                        if args.invert {
+                            windows = windows.inverse();
+                        }
+                        let output_dir = format!("{}/{}", &set.args.output_dir, context);
+                        let distribution = windows.distribution();
+                        let path = format!("{}/{}_distribution.txt", &output_dir, sample);
+                        fs::write(path, distribution)?;
+                        if let Some(matrix) = &windows.matrix {
+                            let path = format!("{}/{}_matrix.tsv", &output_dir, sample);
+                            fs::write(path, matrix.table(&windows.layout, &contigs))?;
+                        }
+                        set_rows.summary += &windows.summary(&sample, context);
+                        set_rows.profile += &windows.profile(&sample, context);
+                        set_rows.features += &windows.features(&sample, context);
+                        if let Some(summaries) = &windows.gene_summaries {
+                            set_rows.genes +=
+                                &summaries.rows(&sample, context, &set.genes, &contigs);
+                        }
+                    }
+                    rows.push(set_rows);
+                }
+                let tile_rows = match &tiling {
+                    Some(tiling) => tiles
+                        .iter()
+                        .map(|t| t.rows(&sample, tiling, &contigs))
+                        .collect(),
+                    None => String::new(),
+                };
+                Ok((rows, tile_rows))
+            })
+            .collect::<Result<_>>()
+    })?;
+    for (s, set) in sets.iter().enumerate() {
+        let output_dir = &set.args.output_dir;
+        let rows = |table: fn(&SetRows) -> &String| {
+            tables
+                .iter()
+                .map(|t| table(&t.0[s]).as_str())
+                .collect::<String>()
         };
-        chromosome_count.into()
-    ];
-    // Put genes into their correct bucket
-    let mut gene_length_sum = 0;
-    let mut sense_gene_count = 0;
-    genes.iter().for_each(|g| {
-        let chromosome = &mut structured_genes[(g.chromosome - 1) as usize];
-        let strand = match &g.strand {
-            Strand::Sense => &mut chromosome.sense,
-            Strand::Antisense => &mut chromosome.antisense,
-        };
-        if g.strand == Strand::Sense {
-            sense_gene_count += 1;
+        fs::write(
+            format!("{}/summary.tsv", output_dir),
+            String::from(SUMMARY_HEADER) + &rows(|r| &r.summary),
+        )?;
+        fs::write(
+            format!("{}/profile.tsv", output_dir),
+            String::from(PROFILE_HEADER) + &rows(|r| &r.profile),
+        )?;
+        if args.transcripts {
+            fs::write(
+                format!("{}/features.tsv", output_dir),
+                String::from(FEATURES_HEADER) + &rows(|r| &r.features),
+            )?;
         }
-        gene_length_sum += g.end - g.start;
-        strand.push(g.to_owned());
-    });
-    let average_gene_length = gene_length_sum / genes.len() as i32;
-    println!(
-        "Average gene length: {} bp, {} genes, of which {} are on the sense strand and {} on the antisense strand",
-        average_gene_length,
-        genes.len(),
-        sense_gene_count,
-        genes.len() - sense_gene_count
-    );
-
-    // Determine the maximum gene length by iterating over all genes
-    let mut max_gene_length: i32 = 100; // if not using absolute window sizes, the maximum gene length will be 100%
-    if args.absolute {
-        for gene in &genes {
-            let length = gene.end - gene.start;
-            if length > max_gene_length {
-                max_gene_length = length
-            }
+        if args.gene_summary {
+            fs::write(
+                format!("{}/genes.tsv", output_dir),
+                String::from(GENES_HEADER) + &rows(|r| &r.genes),
+            )?;
+        }
+
+        if let Some(pedigree) = pedigree.as_ref().filter(|_| args.epimutation) {
+            estimate_epimutation_rates(&set.args, &set.layout, pedigree)?;
         }
-        println!("The maximum gene length is {} bp", max_gene_length);
     }
 
-    set_up_output_dir(max_gene_length, args.clone())?;
-
-    methylome_files.par_iter().try_for_each_with(
-        structured_genes,
-        |genome, (path, filename)| -> Result<()> {
-            let file = open_file(path, filename)?;
-            let mut windows =
-                extract_windows(file, genome.to_vec(), max_gene_length as i32, args.clone())?;
-            if args.invert {
-                windows = windows.inverse();
-            }
-            windows.save(
-                &args.output_dir,
-                filename,
-                args.window_step as usize,
-                args.invert,
-            )?;
-            let distribution = windows.distribution();
-            let path = format!(
-                "{}/{}_distribution.txt",
-                &args.output_dir,
-                filename.to_str().unwrap()
-            );
-            fs::write(path, distribution)?;
-            Ok(())
-        },
-    )?;
+    if tiling.is_some() {
+        let rows: String = tables.iter().map(|t| t.1.as_str()).collect();
+        fs::write(
+            format!("{}/tiles.tsv", &args.output_dir),
+            String::from(TILES_HEADER) + &rows,
+        )?;
+    }
 
     println!("Done in: {:?}", start.elapsed());
     Ok(())
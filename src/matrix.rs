@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::*;
+
+/// Header of the gene-by-window matrices, one row per gene and window containing at least one site
+pub const MATRIX_HEADER: &str =
+    "gene\tcontig\tstart\tend\tstrand\twindow_index\twindow_start_label\tsites\tcoverage\tweighted_level\n";
+
+/// Sites of every gene and window, keeping the gene identity that the windows lose.
+/// Sparse: only cells with sites are stored, as most genes have no sites in most windows.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeneMatrix {
+    /// Name and summaries by window index on the continuous axis from the first upstream to the last downstream window, by gene location like `GeneSummaries`
+    cells: HashMap<GeneKey, (String, BTreeMap<usize, Summary>)>,
+}
+
+impl GeneMatrix {
+    pub fn add(&mut self, gene: &Gene, index: usize, site: &MethylationSite) {
+        let (_, windows) = self
+            .cells
+            .entry(gene.key())
+            .or_insert_with(|| (gene.name.clone(), BTreeMap::new()));
+        windows.entry(index).or_default().add(site);
+    }
+
+    pub fn merge(&mut self, other: GeneMatrix) {
+        for (key, (name, windows)) in other.cells {
+            let (_, merged) = self
+                .cells
+                .entry(key)
+                .or_insert_with(|| (name, BTreeMap::new()));
+            for (index, summary) in windows {
+                merged.entry(index).or_default().merge(&summary);
+            }
+        }
+    }
+
+    /// The matrix with the order of the windows reversed, for an axis of `count` windows
+    pub fn reversed(self, count: usize) -> Self {
+        let cells = self
+            .cells
+            .into_iter()
+            .map(|(key, (name, windows))| {
+                let windows = windows
+                    .into_iter()
+                    .map(|(index, summary)| (count - 1 - index, summary))
+                    .collect();
+                (key, (name, windows))
+            })
+            .collect();
+        GeneMatrix { cells }
+    }
+
+    /// Rows of the matrix in long format, sorted by gene name, location and window, see `MATRIX_HEADER`
+    pub fn table(&self, layout: &Layout, contigs: &Contigs) -> String {
+        let labels: Vec<String> = layout
+            .axis()
+            .into_iter()
+            .flat_map(|region| (0..layout.count(region)).map(move |i| layout.label(region, i)))
+            .collect();
+        let mut output = String::from(MATRIX_HEADER);
+        let mut genes: Vec<(&GeneKey, &String, &BTreeMap<usize, Summary>)> = self
+            .cells
+            .iter()
+            .map(|(key, (name, windows))| (key, name, windows))
+            .collect();
+        genes
+            .sort_by_key(|(key, name, _)| (*name, key.0, key.1, key.2, key.3 == Strand::Antisense));
+        for (key, name, windows) in genes {
+            for (index, summary) in windows {
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    name,
+                    contigs.name(key.0),
+                    key.1,
+                    key.2,
+                    key.3,
+                    index,
+                    labels[*index],
+                    summary.sites,
+                    summary.coverage,
+                    Level(summary.weighted_level())
+                ));
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sparse_gene_matrix() {
+        let args = Args {
+            window_size: 50,
+            absolute: true,
+            cutoff: 100,
+            ..Default::default()
+        };
+        let layout = args.layout(100);
+        let site = |location, methylated| MethylationSite {
+            chromosome: 0,
+            location,
+            strand: Strand::Sense,
+            context: Context::CG,
+            methylated,
+            total: 4,
+            posterior: None,
+            status: None,
+            level: methylated as f32 / 4.0,
+            original: String::new(),
+        };
+        let mut contigs = Contigs::new();
+        contigs.intern("1");
+        contigs.intern("2");
+        let gene = |chromosome, start, name: &str| Gene {
+            chromosome,
+            start,
+            end: start + 100,
+            name: name.to_owned(),
+            strand: Strand::Sense,
+            flanks: None,
+            transcript: None,
+        };
+        let mut matrix = GeneMatrix::default();
+        matrix.add(&gene(0, 300, "AT1G01020"), 2, &site(310, 4));
+        matrix.add(&gene(0, 100, "AT1G01010"), 2, &site(120, 1));
+        matrix.add(&gene(0, 100, "AT1G01010"), 2, &site(130, 3));
+        matrix.add(&gene(0, 100, "AT1G01010"), 5, &site(290, 0));
+        // Genes of the same name at other locations are rows of their own
+        matrix.add(&gene(1, 100, "."), 2, &site(110, 4));
+        matrix.add(&gene(1, 500, "."), 2, &site(510, 0));
+        assert_eq!(
+            matrix.table(&layout, &contigs),
+            String::from(MATRIX_HEADER)
+                + ".\t2\t100\t200\t+\t2\t0bp\t1\t4\t1.0000\n"
+                + ".\t2\t500\t600\t+\t2\t0bp\t1\t4\t0.0000\n"
+                + "AT1G01010\t1\t100\t200\t+\t2\t0bp\t2\t8\t0.5000\n"
+                + "AT1G01010\t1\t100\t200\t+\t5\t+50bp\t1\t4\t0.0000\n"
+                + "AT1G01020\t1\t300\t400\t+\t2\t0bp\t1\t4\t1.0000\n"
+        );
+
+        let reversed = matrix.reversed(6);
+        assert!(reversed
+            .table(&layout, &contigs)
+            .contains("AT1G01010\t1\t100\t200\t+\t0\t-100bp\t1\t4\t"));
+    }
+}
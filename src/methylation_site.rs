@@ -4,141 +4,336 @@ use itertools::Itertools;
 
 use crate::*;
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct MethylationSite {
-    pub chromosome: u8,
+    /// Id of the contig in the `Contigs` dictionary
+    pub chromosome: ContigId,
     pub location: i32,
     pub strand: Strand,
+    pub context: Context,
+    pub methylated: u32,
+    pub total: u32,
+    /// Posterior probability of the methylation status, only known for methimpute files
+    pub posterior: Option<f32>,
+    /// Methylation status as called by methimpute, unknown for Bismark files
+    pub status: Option<Status>,
+    /// Methylation level of the site, NaN if the site is not covered by any read
+    pub level: f32,
+    /// Line of the site in the methimpute layout, written to the output files
     pub original: String,
 }
 
 impl MethylationSite {
-    /// Create a new CG site from a line of a methylation file.
-    /// Only yields a CG site if the line is formatted correctly and is a CG site.
+    /// Create a new methylation site from a line of a methimpute methylation file.
+    /// Only yields a site if the line is formatted correctly.
     /// If invalid, an error is returned.
     ///
     /// One pitfall of this implementation is the `collect tuple` call, which only yields a `Some` value if the line has exactly 9 tab-separated fields.
-    pub fn from_methylome_file_line(s: &str, invert_strand: bool) -> Result<Self> {
+    /// Sites on contigs that are not part of the dictionary yield an error, as they can not belong to any gene.
+    pub fn from_methylome_file_line(
+        s: &str,
+        contigs: &Contigs,
+        invert_strand: bool,
+    ) -> Result<Self> {
         s.split('\t')
             .collect_tuple()
-            .filter(|(_, _, _, context, _, _, _, _, _)| context == &"CG")
-            .map(|(chromosome, location, strand, _, _, _, _, _, _)| {
-                Ok(MethylationSite {
-                    chromosome: chromosome.parse::<u8>()?,
-                    location: location.parse::<i32>()?,
-                    strand: if (strand == "+") ^ invert_strand {
-                        Strand::Sense
-                    } else {
-                        Strand::Antisense
-                    },
-                    original: s.to_owned(),
-                })
+            .map(
+                |(
+                    chromosome,
+                    location,
+                    strand,
+                    context,
+                    methylated,
+                    total,
+                    posterior,
+                    status,
+                    level,
+                )| {
+                    Ok(MethylationSite {
+                        chromosome: contigs.id(chromosome)?,
+                        location: location.parse::<i32>()?,
+                        strand: Strand::from_symbol(strand, invert_strand),
+                        context: context.parse()?,
+                        methylated: methylated.parse()?,
+                        total: total.parse()?,
+                        posterior: posterior.parse().ok(),
+                        status: status.parse().ok(),
+                        level: level.parse().unwrap_or(f32::NAN),
+                        original: s.to_owned(),
+                    })
+                },
+            )
+            .ok_or(Error::CGSite)?
+    }
+
+    /// Create a new methylation site from a line of a Bismark coverage file (`chr start end %meth meth unmeth`).
+    ///
+    /// Coverage files neither record the strand nor the context of a site. Sites are assumed to be CG sites, as this is what `bismark2bedGraph` reports by default. They are created on the sense strand and matched against the genes of both strands, see `MethylomeFormat::is_stranded`.
+    pub fn from_coverage_file_line(
+        s: &str,
+        contigs: &Contigs,
+        invert_strand: bool,
+    ) -> Result<Self> {
+        s.split('\t')
+            .collect_tuple()
+            .map(|(chromosome, start, _, _, methylated, unmethylated)| {
+                let methylated = methylated.parse()?;
+                let total = methylated + unmethylated.parse::<u32>()?;
+                Ok(MethylationSite::new(
+                    chromosome,
+                    contigs.id(chromosome)?,
+                    start.parse::<i32>()?,
+                    Strand::from_symbol("+", invert_strand),
+                    Context::CG,
+                    methylated,
+                    total,
+                ))
             })
             .ok_or(Error::CGSite)?
     }
+
+    /// Create a new methylation site from a line of a Bismark cytosine report (`chr pos strand meth unmeth context trinucleotide`).
+    pub fn from_cx_report_file_line(
+        s: &str,
+        contigs: &Contigs,
+        invert_strand: bool,
+    ) -> Result<Self> {
+        s.split('\t')
+            .collect_tuple()
+            .map(
+                |(chromosome, location, strand, methylated, unmethylated, context, _)| {
+                    let methylated = methylated.parse()?;
+                    let total = methylated + unmethylated.parse::<u32>()?;
+                    Ok(MethylationSite::new(
+                        chromosome,
+                        contigs.id(chromosome)?,
+                        location.parse::<i32>()?,
+                        Strand::from_symbol(strand, invert_strand),
+                        context.parse()?,
+                        methylated,
+                        total,
+                    ))
+                },
+            )
+            .ok_or(Error::CGSite)?
+    }
+
+    /// Create a site from read counts only, as reported by Bismark. The line written to the output files is rendered in the methimpute layout, with the posterior probability and status left as `NA`.
+    fn new(
+        contig: &str,
+        chromosome: ContigId,
+        location: i32,
+        strand: Strand,
+        context: Context,
+        methylated: u32,
+        total: u32,
+    ) -> Self {
+        let mut site = MethylationSite {
+            chromosome,
+            location,
+            strand,
+            context,
+            methylated,
+            total,
+            posterior: None,
+            status: None,
+            level: methylated as f32 / total as f32,
+            original: String::new(),
+        };
+        site.original = site.to_methimpute_line(contig);
+        site
+    }
+
+    /// Render the site as a line in the methimpute layout, the common schema of all output files
+    pub fn to_methimpute_line(&self, contig: &str) -> String {
+        let na = || String::from("NA");
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            contig,
+            self.location,
+            self.strand,
+            self.context,
+            self.methylated,
+            self.total,
+            self.posterior.map_or_else(na, |p| p.to_string()),
+            self.status.as_ref().map_or_else(na, |s| s.to_string()),
+            if self.level.is_nan() {
+                na()
+            } else {
+                format!("{:.4}", self.level)
+            }
+        )
+    }
+
+    /// The same site on the opposite strand. Used for formats without strand information, whose sites are matched against genes on both strands.
+    pub fn mirrored(&self) -> Self {
+        let mut site = self.clone();
+        site.strand = match self.strand {
+            Strand::Sense => Strand::Antisense,
+            Strand::Antisense => Strand::Sense,
+        };
+        let contig = self.original.split('\t').next().unwrap_or_default();
+        site.original = site.to_methimpute_line(contig);
+        site
+    }
+
     /// Checks weather a given CG site belongs to a specific gene. The cutoff is the number of bases upstream and downstream of the gene to consider the CG site in the gene. For example, a cutoff of 1000 would consider a CG site 1000 bases upstream of the gene to be in the gene.
     /// To strictly check weather a CG site is within the gene region, pass a cutoff of 0.
     ///
     /// Passing a negative cutoff is possible but leads to undefined behaviour if used together with ``find_gene``.
     pub fn is_in_gene(&self, gene: &Gene, cutoff: i32) -> bool {
+        let (from, to) = gene.bounds(cutoff, cutoff);
         self.chromosome == gene.chromosome
-            && gene.start <= self.location + cutoff
-            && self.location <= gene.end + cutoff
+            && from <= self.location
+            && self.location <= to
             && self.strand == gene.strand
     }
 
-    /// Find the gene within a genome that a CG site belongs to. Due to binary search, searching is O(log n) where n is the number of genes in the genome.
-    /// Therefore, this method is efficient to use on large genomes.
+    /// Find all genes within a genome that a CG site belongs to, including their upstream and downstream regions, ordered by gene start.
+    /// Uses the interval index of the genes: a binary search for the genes starting before the site, followed by a backwards scan that stops once the running maximum of the gene ends falls below the site.
+    /// The scan is short for most annotations, but a long gene early on a contig keeps the running maximum up, so that a lookup behind it scans every gene since, up to O(n) for n genes on the contig.
+    /// Sorted methylomes are matched with `Sweep` instead, which visits every gene once per methylome.
     ///
     /// The lifetime of the genome is longer than the lifetime of the CG site.
     /// GG sites exist only while a single methylation file is being processed but the genome is loaded once and exists for the entire program
-    pub fn find_gene<'short, 'long>(
-        &'short self,
+    pub fn find_genes<'long>(
+        &self,
         genome: &'long [GenesByStrand],
-        cutoff: i32,
-    ) -> Option<&'long Gene> {
-        let chromosome = &genome[(self.chromosome - 1) as usize];
-        let strand = match self.strand {
-            Strand::Sense => &chromosome.sense,
-            Strand::Antisense => &chromosome.antisense,
-        };
-        let first_matching_gene_index = strand
-            .binary_search_by_key(&self.location, |gene| gene.end + cutoff)
-            .unwrap_or_else(|x| x); // Collapse exact match on gene end and closest previous match into one, as both are valid
-        if strand.len() < first_matching_gene_index + 1 {
-            return None;
+        upstream: i32,
+        downstream: i32,
+    ) -> Vec<&'long Gene> {
+        match genome.get(self.chromosome) {
+            Some(chromosome) => {
+                chromosome.overlapping(&self.strand, self.location, upstream, downstream)
+            }
+            None => Vec::new(),
         }
-
-        let gene = &strand[first_matching_gene_index];
-        if self.is_in_gene(gene, cutoff) {
-            return Some(gene);
+    }
+    /// Region of a gene that a site lies in, upstream, within or downstream of the gene in the direction of its strand
+    pub fn region(&self, gene: &Gene) -> Region {
+        // Offset from start for + strand, offset from end for - strand. Can be negative for upstream sites
+        let offset = match &self.strand {
+            Strand::Sense => self.location - gene.start,
+            Strand::Antisense => gene.end - self.location,
+        };
+        match offset {
+            x if x < 0 => Region::Upstream,
+            x if x > gene.end - gene.start => Region::Downstream, // CG site exactly on the end of the gene is still considered in the gene
+            _ => Region::Gene,
         }
-
-        None
     }
+
     /// Place a CG site in the correct windows. Returns a list of all the successfull insertions as a tuple of the region (upstream, downstream or gene) and the index of the window.
     ///
     /// It works by first finding the region the CG site is in (upstream, downstream or gene) and then finding the windows within that a CG site belongs to.
     /// For genes on the - strand, the windows are reversed, so that the first window is the one closest to the end of the gene.
+    /// Each region is divided according to its own layout in the windows. Gene body sites of genes with a transcript model are also placed in the spliced windows, see `place_in_transcript`.
     pub fn place_in_windows(
         &self,
         gene: &Gene,
         windows: &mut Windows,
-        args: &Args,
-    ) -> Vec<(Region, usize)> // Return a vector of (strand, window) tuples for each window the CG site is in
+    ) -> Result<Vec<(Region, usize)>> // Return a vector of (strand, window) tuples for each window the CG site is in
     {
-        const E: f32 = 0.1; // Epsilon for floating point comparison
         let location = self.location as f32;
-        let cutoff = args.cutoff as f32;
-        let step = args.window_step as f32;
-        let size = args.window_size as f32;
+        // Effective lengths of the flanks, shorter than the region length if truncated at a neighbouring gene
+        let upstream = gene.upstream(windows.layout.upstream.length) as f32;
+        let downstream = gene.downstream(windows.layout.downstream.length) as f32;
         let start = gene.start as f32;
         let end = gene.end as f32;
         let length = end - start;
 
-        // Offset from start for + strand, offset from end for - strand. Can be negative for upstream sites
-        let offset = match &self.strand {
-            Strand::Sense => location - start,
-            Strand::Antisense => end - location,
-        };
-        let mut windows_in = Vec::new();
-        let region = match offset {
-            x if x < 0.0 => Region::Upstream,
-            x if x > length => Region::Downstream, // CG site exactly on the end of the gene is still considered in the gene
-            _ => Region::Gene,
-        };
-        let local_windows = windows.get_mut(&region);
+        let region = self.region(gene);
+        // Anchored profiles place all sites by their signed distance to the anchor on a single axis
+        if let Some(anchor) = windows.layout.anchor {
+            let anchor = match (anchor, &self.strand) {
+                (Anchor::Tss, Strand::Sense) | (Anchor::Tes, Strand::Antisense) => start,
+                (Anchor::Tes, Strand::Sense) | (Anchor::Tss, Strand::Antisense) => end,
+            };
+            let distance = match &self.strand {
+                Strand::Sense => location - anchor,
+                Strand::Antisense => anchor - location,
+            };
+            let mut windows_in = self.place_on_axis(distance, Region::Gene, windows)?;
+            windows_in.extend(self.place_in_transcript(region, gene, windows)?);
+            return Ok(windows_in);
+        }
+
+        let layout = *windows.layout.get(region);
 
-        // let max = if args.absolute { gene_length } else { 100 };
         let mut position = match (&region, &self.strand) {
             // Position within the region of the gene, switched start & end for - strand
-            (Region::Upstream, Strand::Sense) => location - start + cutoff,
-            (Region::Gene, Strand::Sense) => location - start,
+            (Region::Upstream, Strand::Sense) => location - start + upstream,
+            (Region::Gene | Region::Spliced, Strand::Sense) => location - start,
             (Region::Downstream, Strand::Sense) => location - end,
-            (Region::Upstream, Strand::Antisense) => end - location + cutoff,
-            (Region::Gene, Strand::Antisense) => end - location,
+            (Region::Upstream, Strand::Antisense) => end - location + upstream,
+            (Region::Gene | Region::Spliced, Strand::Antisense) => end - location,
             (Region::Downstream, Strand::Antisense) => start - location,
         };
 
-        if !args.absolute {
+        if layout.absolute {
+            // Upstream windows are counted from the start of the full-length flank, so that they stay aligned to the gene if the flank is truncated
+            if region == Region::Upstream {
+                position += layout.length as f32 - upstream;
+            }
+        } else {
+            // Percentages of the flanks are relative to their effective length
             position = match region {
-                Region::Upstream => position / cutoff,
-                Region::Gene => position / length,
-                Region::Downstream => position / cutoff,
+                Region::Upstream => position / upstream,
+                Region::Gene | Region::Spliced => position / length,
+                Region::Downstream => position / downstream,
             };
             position *= 100.0; // Normalize to 0-100%
         }
+        let mut windows_in = self.place_on_axis(position, region, windows)?;
+        windows_in.extend(self.place_in_transcript(region, gene, windows)?);
+        Ok(windows_in)
+    }
+
+    /// Count a gene body site towards its feature class and place exonic sites in the spliced windows, if the gene has a transcript model
+    fn place_in_transcript(
+        &self,
+        region: Region,
+        gene: &Gene,
+        windows: &mut Windows,
+    ) -> Result<Vec<(Region, usize)>> {
+        let Some(transcript) = gene.transcript.as_ref().filter(|_| region == Region::Gene) else {
+            return Ok(Vec::new());
+        };
+        let class = transcript.classify(self.location, &self.strand);
+        windows.features[class as usize].add(self);
 
-        for (i, window) in local_windows.iter_mut().enumerate() {
-            let lower_bound = i as f32 * step - E;
-            let upper_bound = lower_bound + size + E;
+        let Some(offset) = transcript.spliced_offset(self.location, &self.strand) else {
+            return Ok(Vec::new());
+        };
+        let position = if windows.layout.gene.absolute {
+            offset as f32
+        } else {
+            offset as f32 / transcript.spliced_length() as f32 * 100.0
+        };
+        self.place_on_axis(position, Region::Spliced, windows)
+    }
+
+    /// Place a CG site in all windows of a region that contain its position on the window axis of the region.
+    /// Windows include both of their bounds, so a site on the boundary of two adjacent windows is placed in both. The bins of `Tiles` are half-open instead, as they count sites of the whole genome rather than profile genes.
+    fn place_on_axis(
+        &self,
+        position: f32,
+        region: Region,
+        windows: &mut Windows,
+    ) -> Result<Vec<(Region, usize)>> {
+        const E: f32 = 0.1; // Epsilon for floating point comparison
+        let layout = *windows.layout.get(region);
+        let mut windows_in = Vec::new();
+        for i in 0..windows.get(region).len() {
+            let lower_bound = layout.start(i) as f32 - E;
+            let upper_bound = lower_bound + layout.size as f32 + E;
 
             if position >= lower_bound && position <= upper_bound {
-                window.push(self.clone());
-                windows_in.push((region.clone(), i));
+                windows.push(region, i, self)?;
+                windows_in.push((region, i));
             }
         }
-        windows_in
+        Ok(windows_in)
     }
 }
 
@@ -159,90 +354,145 @@ mod tests {
     use crate::*;
 
     const GENE: Gene = Gene {
-        chromosome: 1,
+        chromosome: 0,
         start: 50,
         end: 100,
         strand: Strand::Sense,
         name: String::new(),
+        flanks: None,
+        transcript: None,
     };
     const WITHIN_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 80,
         strand: Strand::Sense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
 
     const OPPOSITE_STRAND_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 80,
         strand: Strand::Antisense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
 
     const HIGHER_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 150,
         strand: Strand::Sense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
     const LOWER_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 0,
         strand: Strand::Sense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
     const ANTI_GENE: Gene = Gene {
-        chromosome: 1,
+        chromosome: 0,
         start: 50,
         end: 100,
         strand: Strand::Antisense,
         name: String::new(),
+        flanks: None,
+        transcript: None,
     };
     const ANTI_WITHIN_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 80,
         strand: Strand::Antisense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
 
     const ANTI_OPPOSITE_STRAND_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 80,
         strand: Strand::Sense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
 
     const ANTI_HIGHER_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 150,
         strand: Strand::Antisense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
     const ANTI_LOWER_CG: MethylationSite = MethylationSite {
-        chromosome: 1,
+        chromosome: 0,
         location: 0,
         strand: Strand::Antisense,
         original: String::new(),
+        context: Context::CG,
+        methylated: 0,
+        total: 0,
+        posterior: None,
+        status: None,
+        level: 0.0,
     };
 
     #[test]
     fn test_instantiate_from_methylome_file_line() {
-        let line = "1	23151	+	CG	0	8	0.9999	U	0.0025";
-        let cg = MethylationSite::from_methylome_file_line(line, false).unwrap();
+        let mut contigs = Contigs::new();
+        contigs.intern("Chr2");
+        contigs.intern("Chr1");
+        let line = "Chr1	23151	+	CG	0	8	0.9999	U	0.0025";
+        let cg = MethylationSite::from_methylome_file_line(line, &contigs, false).unwrap();
         assert_eq!(cg.chromosome, 1);
     }
 
     #[test]
     fn test_instantiate_from_methylome_file_line_invalid_line() {
         let line = "1	23151	+	CG	0	8	0.9999	";
-        let cg = MethylationSite::from_methylome_file_line(line, false);
+        let cg = MethylationSite::from_methylome_file_line(line, &Contigs::new(), false);
         assert!(cg.is_err());
     }
 
     #[test]
     fn test_instantiate_from_methylome_file_line_invalid_chromosome() {
         let line = "X	23151	+	CG	0	8	0.9999	U	0.0025";
-        let cg = MethylationSite::from_methylome_file_line(line, false);
+        let cg = MethylationSite::from_methylome_file_line(line, &Contigs::new(), false);
         assert!(cg.is_err());
     }
 
@@ -256,34 +506,72 @@ mod tests {
     }
 
     #[test]
-    fn test_find_gene() {
+    fn test_is_in_gene_antisense() {
+        assert!(ANTI_WITHIN_CG.is_in_gene(&ANTI_GENE, 0));
+        assert!(!ANTI_OPPOSITE_STRAND_CG.is_in_gene(&ANTI_GENE, 0));
+        assert!(!ANTI_HIGHER_CG.is_in_gene(&ANTI_GENE, 0));
+        assert!(ANTI_HIGHER_CG.is_in_gene(&ANTI_GENE, 50));
+        assert!(!ANTI_LOWER_CG.is_in_gene(&ANTI_GENE, 0));
+        assert!(ANTI_LOWER_CG.is_in_gene(&ANTI_GENE, 50));
+    }
+
+    #[test]
+    fn test_find_genes() {
         let mut genes = GenesByStrand::new();
         for i in 0..100 {
             genes.insert(Gene {
-                chromosome: 1,
+                chromosome: 0,
                 start: i,
                 end: i + 50,
                 strand: Strand::Sense,
                 name: String::new(),
+                flanks: None,
+                transcript: None,
             });
         }
+        genes.sort();
 
         let genome = vec![genes.clone()];
-        assert!(OPPOSITE_STRAND_CG.find_gene(&genome, 0).is_none());
+        assert!(OPPOSITE_STRAND_CG.find_genes(&genome, 0, 0).is_empty());
+        let starts = |found: Vec<&Gene>| found.iter().map(|g| g.start).collect::<Vec<_>>();
         assert_eq!(
-            Some(WITHIN_CG.find_gene(&genome, 0)),
-            Some(genes.sense.get(30))
+            starts(WITHIN_CG.find_genes(&genome, 0, 0)),
+            (30..=80).collect::<Vec<_>>()
         );
         assert_eq!(
-            Some(HIGHER_CG.find_gene(&genome, 50)),
-            Some(genes.sense.get(50))
+            starts(HIGHER_CG.find_genes(&genome, 50, 50)),
+            (50..100).collect::<Vec<_>>()
         );
         assert_eq!(
-            Some(LOWER_CG.find_gene(&genome, 50)),
-            Some(genes.sense.get(0))
+            starts(LOWER_CG.find_genes(&genome, 50, 50)),
+            (0..=50).collect::<Vec<_>>()
         );
     }
 
+    #[test]
+    fn test_find_nested_genes() {
+        let mut genes = GenesByStrand::new();
+        for (start, end) in [(0, 1000), (10, 20), (60, 70), (500, 600)] {
+            genes.insert(Gene {
+                chromosome: 0,
+                start,
+                end,
+                strand: Strand::Sense,
+                name: String::new(),
+                flanks: None,
+                transcript: None,
+            });
+        }
+        genes.sort();
+
+        // The long gene is found although the short genes in between end before the site
+        let genome = vec![genes];
+        let found = WITHIN_CG.find_genes(&genome, 0, 0);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].end, 1000);
+        assert_eq!(WITHIN_CG.find_genes(&genome, 20, 20).len(), 2);
+    }
+
     #[test]
     fn test_extract_gene() {}
 
@@ -298,56 +586,73 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let all_within_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 1000,
             end: 2000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_upstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 2000,
             end: 3000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_downstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 0,
             end: 1000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let mut windows = Windows::new(1000, &args);
         for i in 1..1000 {
             let cg = MethylationSite {
-                chromosome: 1,
+                chromosome: 0,
                 location: i + 1000,
                 strand: Strand::Sense,
                 original: String::new(),
+                context: Context::CG,
+                methylated: 0,
+                total: 0,
+                posterior: None,
+                status: None,
+                level: 0.0,
             };
-            let upstream = cg.place_in_windows(&all_upstream_gene, &mut windows, &args);
-            let gene = cg.place_in_windows(&all_within_gene, &mut windows, &args);
-            let downstream = cg.place_in_windows(&all_downstream_gene, &mut windows, &args);
+            let upstream = cg
+                .place_in_windows(&all_upstream_gene, &mut windows)
+                .unwrap();
+            let gene = cg.place_in_windows(&all_within_gene, &mut windows).unwrap();
+            let downstream = cg
+                .place_in_windows(&all_downstream_gene, &mut windows)
+                .unwrap();
 
             println!("Placing {}", i);
             println!("Upstream: {:?}", upstream);
             println!("Gene: {:?}", gene);
             println!("Downstream: {:?}", downstream);
-            assert!(windows.upstream[i as usize].contains(&cg));
-            assert!(windows.gene[i as usize].contains(&cg));
-            assert!(windows.downstream[i as usize].contains(&cg));
+            assert!(windows.upstream[i as usize].is_buffered(&cg));
+            assert!(windows.gene[i as usize].is_buffered(&cg));
+            assert!(windows.downstream[i as usize].is_buffered(&cg));
 
             if i > 3 {
-                assert!(windows.upstream[i as usize - 1].contains(&cg));
-                assert!(windows.gene[i as usize - 1].contains(&cg));
-                assert!(windows.downstream[i as usize - 1].contains(&cg));
-                assert!(windows.upstream[i as usize - 2].contains(&cg));
-                assert!(windows.gene[i as usize - 2].contains(&cg));
-                assert!(windows.downstream[i as usize - 2].contains(&cg));
+                assert!(windows.upstream[i as usize - 1].is_buffered(&cg));
+                assert!(windows.gene[i as usize - 1].is_buffered(&cg));
+                assert!(windows.downstream[i as usize - 1].is_buffered(&cg));
+                assert!(windows.upstream[i as usize - 2].is_buffered(&cg));
+                assert!(windows.gene[i as usize - 2].is_buffered(&cg));
+                assert!(windows.downstream[i as usize - 2].is_buffered(&cg));
             }
         }
     }
@@ -362,48 +667,65 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let all_within_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 100,
             end: 200,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_upstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 200,
             end: 300,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_downstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 0,
             end: 100,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let mut windows = Windows::new(100, &args);
         for i in 1..100 {
             let cg = MethylationSite {
-                chromosome: 1,
+                chromosome: 0,
                 location: i + 100,
                 strand: Strand::Sense,
                 original: String::new(),
+                context: Context::CG,
+                methylated: 0,
+                total: 0,
+                posterior: None,
+                status: None,
+                level: 0.0,
             };
-            let upstream = cg.place_in_windows(&all_upstream_gene, &mut windows, &args);
-            let gene = cg.place_in_windows(&all_within_gene, &mut windows, &args);
-            let downstream = cg.place_in_windows(&all_downstream_gene, &mut windows, &args);
+            let upstream = cg
+                .place_in_windows(&all_upstream_gene, &mut windows)
+                .unwrap();
+            let gene = cg.place_in_windows(&all_within_gene, &mut windows).unwrap();
+            let downstream = cg
+                .place_in_windows(&all_downstream_gene, &mut windows)
+                .unwrap();
 
             println!("Placing {}", i);
             println!("Upstream: {:?}", upstream);
             println!("Gene: {:?}", gene);
             println!("Downstream: {:?}", downstream);
-            assert!(windows.upstream[i as usize].contains(&cg));
-            assert!(windows.gene[i as usize].contains(&cg));
-            assert!(windows.downstream[i as usize].contains(&cg));
+            assert!(windows.upstream[i as usize].is_buffered(&cg));
+            assert!(windows.gene[i as usize].is_buffered(&cg));
+            assert!(windows.downstream[i as usize].is_buffered(&cg));
         }
     }
     #[test]
@@ -417,110 +739,177 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let all_within_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 1000,
             end: 2000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_upstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 2000,
             end: 3000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_downstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 0,
             end: 1000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let mut windows = Windows::new(1000, &args);
         assert!(windows.upstream.len() == 100);
         for i in 1..1000 {
             let cg = MethylationSite {
-                chromosome: 1,
+                chromosome: 0,
                 location: i + 1000,
                 strand: Strand::Sense,
                 original: String::new(),
+                context: Context::CG,
+                methylated: 0,
+                total: 0,
+                posterior: None,
+                status: None,
+                level: 0.0,
             };
-            let upstream = cg.place_in_windows(&all_upstream_gene, &mut windows, &args);
-            let gene = cg.place_in_windows(&all_within_gene, &mut windows, &args);
-            let downstream = cg.place_in_windows(&all_downstream_gene, &mut windows, &args);
+            let upstream = cg
+                .place_in_windows(&all_upstream_gene, &mut windows)
+                .unwrap();
+            let gene = cg.place_in_windows(&all_within_gene, &mut windows).unwrap();
+            let downstream = cg
+                .place_in_windows(&all_downstream_gene, &mut windows)
+                .unwrap();
 
             println!("Placing {}", i);
             println!("Upstream: {:?}", upstream);
             println!("Gene: {:?}", gene);
             println!("Downstream: {:?}", downstream);
             println!("{}: {}", i / 10, windows.upstream[(i / 10) as usize].len());
-            assert!(windows.upstream[(i / 10) as usize].contains(&cg));
-            assert!(windows.gene[(i / 10) as usize].contains(&cg));
-            assert!(windows.downstream[(i / 10) as usize].contains(&cg));
+            assert!(windows.upstream[(i / 10) as usize].is_buffered(&cg));
+            assert!(windows.gene[(i / 10) as usize].is_buffered(&cg));
+            assert!(windows.downstream[(i / 10) as usize].is_buffered(&cg));
         }
     }
 
     #[test]
     fn test_place_site() {
         let cg_a = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 80,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_b = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 100,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_c = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 123,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_d = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 200,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_e = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 201,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_f = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 512 + 100 + 100,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_g = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 1024 + 100 + 100,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_h = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 2048 + 100 + 100,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
 
         let gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 100,
             end: 200,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let args = Args {
@@ -532,76 +921,115 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let mut windows = Windows::new(1000, &args);
 
-        cg_a.place_in_windows(&gene, &mut windows, &args);
-        cg_b.place_in_windows(&gene, &mut windows, &args);
-        cg_c.place_in_windows(&gene, &mut windows, &args);
-        cg_d.place_in_windows(&gene, &mut windows, &args);
-        cg_e.place_in_windows(&gene, &mut windows, &args);
-        cg_f.place_in_windows(&gene, &mut windows, &args);
-        cg_g.place_in_windows(&gene, &mut windows, &args);
-        cg_h.place_in_windows(&gene, &mut windows, &args);
+        cg_a.place_in_windows(&gene, &mut windows).unwrap();
+        cg_b.place_in_windows(&gene, &mut windows).unwrap();
+        cg_c.place_in_windows(&gene, &mut windows).unwrap();
+        cg_d.place_in_windows(&gene, &mut windows).unwrap();
+        cg_e.place_in_windows(&gene, &mut windows).unwrap();
+        cg_f.place_in_windows(&gene, &mut windows).unwrap();
+        cg_g.place_in_windows(&gene, &mut windows).unwrap();
+        cg_h.place_in_windows(&gene, &mut windows).unwrap();
 
         println!("{}", windows);
-        assert!(windows.upstream[98].contains(&cg_a));
-        assert!(windows.upstream[99].contains(&cg_a));
-        assert!(windows.gene[0].contains(&cg_b));
-        assert!(windows.gene[21].contains(&cg_c));
-        assert!(windows.gene[22].contains(&cg_c));
-        assert!(windows.gene[23].contains(&cg_c));
-        assert!(windows.gene[99].contains(&cg_d));
-        assert!(windows.downstream[0].contains(&cg_e));
-        assert!(windows.downstream[24].contains(&cg_f));
-        assert!(windows.downstream[49].contains(&cg_g));
-        assert!(windows.downstream[99].contains(&cg_h));
+        assert!(windows.upstream[98].is_buffered(&cg_a));
+        assert!(windows.upstream[99].is_buffered(&cg_a));
+        assert!(windows.gene[0].is_buffered(&cg_b));
+        assert!(windows.gene[21].is_buffered(&cg_c));
+        assert!(windows.gene[22].is_buffered(&cg_c));
+        assert!(windows.gene[23].is_buffered(&cg_c));
+        assert!(windows.gene[99].is_buffered(&cg_d));
+        assert!(windows.downstream[0].is_buffered(&cg_e));
+        assert!(windows.downstream[24].is_buffered(&cg_f));
+        assert!(windows.downstream[49].is_buffered(&cg_g));
+        assert!(windows.downstream[99].is_buffered(&cg_h));
     }
     #[test]
     fn test_place_site_absolute_2() {
         let cg_a = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 80,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_b = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 100,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_c = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 123,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_d = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 200,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_e = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 201,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
         let cg_f = MethylationSite {
-            chromosome: 1,
+            chromosome: 0,
             location: 220,
             strand: Strand::Sense,
             original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
         };
 
         let gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 100,
             end: 200,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let args = Args {
@@ -613,28 +1041,29 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let mut windows = Windows::new(100, &args);
 
-        cg_a.place_in_windows(&gene, &mut windows, &args);
-        cg_b.place_in_windows(&gene, &mut windows, &args);
-        cg_c.place_in_windows(&gene, &mut windows, &args);
-        cg_d.place_in_windows(&gene, &mut windows, &args);
-        cg_e.place_in_windows(&gene, &mut windows, &args);
-        cg_f.place_in_windows(&gene, &mut windows, &args);
-        assert!(windows.upstream[2026].contains(&cg_a));
-        assert!(windows.upstream[2027].contains(&cg_a));
-        assert!(windows.upstream[2028].contains(&cg_a));
-        assert!(windows.gene[0].contains(&cg_b));
-        assert!(windows.gene[21].contains(&cg_c));
-        assert!(windows.gene[22].contains(&cg_c));
-        assert!(windows.gene[23].contains(&cg_c));
-        assert!(windows.gene[99].contains(&cg_d));
-        assert!(windows.downstream[0].contains(&cg_e));
-        assert!(windows.downstream[1].contains(&cg_e));
-        assert!(windows.downstream[18].contains(&cg_f));
-        assert!(windows.downstream[19].contains(&cg_f));
-        assert!(windows.downstream[20].contains(&cg_f));
+        cg_a.place_in_windows(&gene, &mut windows).unwrap();
+        cg_b.place_in_windows(&gene, &mut windows).unwrap();
+        cg_c.place_in_windows(&gene, &mut windows).unwrap();
+        cg_d.place_in_windows(&gene, &mut windows).unwrap();
+        cg_e.place_in_windows(&gene, &mut windows).unwrap();
+        cg_f.place_in_windows(&gene, &mut windows).unwrap();
+        assert!(windows.upstream[2026].is_buffered(&cg_a));
+        assert!(windows.upstream[2027].is_buffered(&cg_a));
+        assert!(windows.upstream[2028].is_buffered(&cg_a));
+        assert!(windows.gene[0].is_buffered(&cg_b));
+        assert!(windows.gene[21].is_buffered(&cg_c));
+        assert!(windows.gene[22].is_buffered(&cg_c));
+        assert!(windows.gene[23].is_buffered(&cg_c));
+        assert!(windows.gene[99].is_buffered(&cg_d));
+        assert!(windows.downstream[0].is_buffered(&cg_e));
+        assert!(windows.downstream[1].is_buffered(&cg_e));
+        assert!(windows.downstream[18].is_buffered(&cg_f));
+        assert!(windows.downstream[19].is_buffered(&cg_f));
+        assert!(windows.downstream[20].is_buffered(&cg_f));
     }
 
     #[test]
@@ -648,41 +1077,58 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let all_within_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 1000,
             end: 2000,
             strand: Strand::Antisense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_upstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 2000,
             end: 3000,
             strand: Strand::Antisense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_downstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 0,
             end: 1000,
             strand: Strand::Antisense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let mut windows = Windows::new(1000, &args);
         assert!(windows.upstream.len() == 100);
         for i in 1..1000 {
             let cg = MethylationSite {
-                chromosome: 1,
+                chromosome: 0,
                 location: i + 1000,
                 strand: Strand::Antisense,
                 original: String::new(),
+                context: Context::CG,
+                methylated: 0,
+                total: 0,
+                posterior: None,
+                status: None,
+                level: 0.0,
             };
-            let upstream = cg.place_in_windows(&all_upstream_gene, &mut windows, &args);
-            let gene = cg.place_in_windows(&all_within_gene, &mut windows, &args);
-            let downstream = cg.place_in_windows(&all_downstream_gene, &mut windows, &args);
+            let upstream = cg
+                .place_in_windows(&all_upstream_gene, &mut windows)
+                .unwrap();
+            let gene = cg.place_in_windows(&all_within_gene, &mut windows).unwrap();
+            let downstream = cg
+                .place_in_windows(&all_downstream_gene, &mut windows)
+                .unwrap();
 
             println!("Placing {}", i);
             println!("Upstream: {:?}", upstream);
@@ -693,11 +1139,118 @@ mod tests {
                 (999 - i) / 10,
                 windows.upstream[(i / 10) as usize].len()
             );
-            assert!(windows.upstream[((999 - i) / 10) as usize].contains(&cg));
-            assert!(windows.gene[((999 - i) / 10) as usize].contains(&cg));
-            assert!(windows.downstream[((999 - i) / 10) as usize].contains(&cg));
+            assert!(windows.upstream[((999 - i) / 10) as usize].is_buffered(&cg));
+            assert!(windows.gene[((999 - i) / 10) as usize].is_buffered(&cg));
+            assert!(windows.downstream[((999 - i) / 10) as usize].is_buffered(&cg));
         }
     }
+    #[test]
+    fn test_place_site_truncated_flanks() {
+        let args = Args {
+            cutoff: 1000,
+            window_size: 10,
+            window_step: 10,
+            ..Default::default()
+        };
+        let gene = Gene {
+            chromosome: 0,
+            start: 1000,
+            end: 2000,
+            strand: Strand::Sense,
+            name: String::new(),
+            flanks: Some(Flanks {
+                upstream: 100,
+                downstream: 1000,
+            }),
+            transcript: None,
+        };
+        let site = |location| MethylationSite {
+            chromosome: 0,
+            location,
+            strand: Strand::Sense,
+            original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
+        };
+        let mut windows = Windows::new(100, &args);
+        // Halfway through the truncated upstream flank
+        assert_eq!(
+            site(950).place_in_windows(&gene, &mut windows).unwrap(),
+            vec![(Region::Upstream, 4), (Region::Upstream, 5)]
+        );
+        assert!(!site(850).is_in_gene(&gene, args.cutoff));
+        assert!(site(2900).is_in_gene(&gene, args.cutoff));
+    }
+
+    #[test]
+    fn test_place_site_anchored() {
+        let args = Args {
+            cutoff: 100,
+            window_size: 50,
+            anchor: Some(Anchor::Tss),
+            ..Default::default()
+        };
+        let gene = |strand| Gene {
+            chromosome: 0,
+            start: 1000,
+            end: 2000,
+            strand,
+            name: String::new(),
+            flanks: None,
+            transcript: None,
+        };
+        let site = |location, strand| MethylationSite {
+            chromosome: 0,
+            location,
+            strand,
+            original: String::new(),
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
+        };
+        let mut windows = Windows::new(1000, &args);
+        assert_eq!(windows.gene.len(), 4);
+        // 20 bp upstream of the TSS, on both strands
+        let upstream = vec![(Region::Gene, 1)];
+        assert_eq!(
+            site(980, Strand::Sense)
+                .place_in_windows(&gene(Strand::Sense), &mut windows)
+                .unwrap(),
+            upstream
+        );
+        assert_eq!(
+            site(2020, Strand::Antisense)
+                .place_in_windows(&gene(Strand::Antisense), &mut windows)
+                .unwrap(),
+            upstream
+        );
+        // Outside of the range
+        assert!(site(1500, Strand::Sense)
+            .place_in_windows(&gene(Strand::Sense), &mut windows)
+            .unwrap()
+            .is_empty());
+
+        let args = Args {
+            anchor: Some(Anchor::Tes),
+            ..args
+        };
+        let mut windows = Windows::new(1000, &args);
+        assert_eq!(
+            site(2070, Strand::Sense)
+                .place_in_windows(&gene(Strand::Sense), &mut windows)
+                .unwrap(),
+            vec![(Region::Gene, 3)]
+        );
+        assert_eq!(windows.layout.label(Region::Gene, 0), "-100bp");
+    }
+
     #[test]
     fn test_place_site_absolute_invert() {
         let args = Args {
@@ -709,50 +1262,67 @@ mod tests {
             output_dir: String::from("also not relevant"),
             window_size: 2,
             window_step: 1,
+            ..Default::default()
         };
         let all_within_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 1000,
             end: 2000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_upstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 2000,
             end: 3000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
         let all_downstream_gene = Gene {
-            chromosome: 1,
+            chromosome: 0,
             start: 0,
             end: 1000,
             strand: Strand::Sense,
             name: String::new(),
+            flanks: None,
+            transcript: None,
         };
 
         let mut windows = Windows::new(1000, &args);
         assert!(windows.upstream.len() == 1000);
         for i in 1..1000 {
             let cg = MethylationSite {
-                chromosome: 1,
+                chromosome: 0,
                 location: i + 1000,
                 strand: Strand::Sense,
                 original: String::new(),
+                context: Context::CG,
+                methylated: 0,
+                total: 0,
+                posterior: None,
+                status: None,
+                level: 0.0,
             };
-            let upstream = cg.place_in_windows(&all_upstream_gene, &mut windows, &args);
-            let gene = cg.place_in_windows(&all_within_gene, &mut windows, &args);
-            let downstream = cg.place_in_windows(&all_downstream_gene, &mut windows, &args);
+            let upstream = cg
+                .place_in_windows(&all_upstream_gene, &mut windows)
+                .unwrap();
+            let gene = cg.place_in_windows(&all_within_gene, &mut windows).unwrap();
+            let downstream = cg
+                .place_in_windows(&all_downstream_gene, &mut windows)
+                .unwrap();
 
             println!("Placing {}", i);
             println!("Upstream: {:?}", upstream);
             println!("Gene: {:?}", gene);
             println!("Downstream: {:?}", downstream);
             println!("{}: {}", (i), windows.upstream[(i) as usize].len());
-            assert!(windows.upstream[(i) as usize].contains(&cg));
-            assert!(windows.gene[(i) as usize].contains(&cg));
-            assert!(windows.downstream[(i) as usize].contains(&cg));
+            assert!(windows.upstream[(i) as usize].is_buffered(&cg));
+            assert!(windows.gene[(i) as usize].is_buffered(&cg));
+            assert!(windows.downstream[(i) as usize].is_buffered(&cg));
         }
     }
 }
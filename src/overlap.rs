@@ -0,0 +1,100 @@
+use clap::ValueEnum;
+
+use crate::*;
+
+/// How to assign a site that lies in the region of more than one gene, e.g. in the flank of one gene and the body of another or within nested genes
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Assign the site to every gene it overlaps
+    #[default]
+    All,
+    /// Assign the site to the gene with the closest transcription start site
+    Closest,
+    /// Assign the site to the genes whose body contains it, falling back to all flanks if it is in no gene body
+    PreferBody,
+    /// Skip sites overlapping more than one gene
+    Discard,
+}
+
+impl OverlapPolicy {
+    /// Select the genes a site is assigned to from all genes it overlaps
+    pub fn select<'long>(
+        &self,
+        site: &MethylationSite,
+        genes: Vec<&'long Gene>,
+    ) -> Vec<&'long Gene> {
+        if genes.len() < 2 {
+            return genes;
+        }
+        match self {
+            OverlapPolicy::All => genes,
+            OverlapPolicy::Closest => {
+                let tss = |g: &Gene| match g.strand {
+                    Strand::Sense => g.start,
+                    Strand::Antisense => g.end,
+                };
+                genes
+                    .into_iter()
+                    .min_by_key(|g| (tss(g) - site.location).abs())
+                    .into_iter()
+                    .collect()
+            }
+            OverlapPolicy::PreferBody => {
+                let bodies: Vec<&Gene> = genes
+                    .iter()
+                    .copied()
+                    .filter(|g| site.is_in_gene(g, 0))
+                    .collect();
+                if bodies.is_empty() {
+                    genes
+                } else {
+                    bodies
+                }
+            }
+            OverlapPolicy::Discard => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OverlapPolicy;
+    use crate::*;
+
+    fn gene(start: i32, end: i32) -> Gene {
+        Gene {
+            chromosome: 0,
+            start,
+            end,
+            name: format!("{start}-{end}"),
+            strand: Strand::Sense,
+            flanks: None,
+            transcript: None,
+        }
+    }
+
+    #[test]
+    fn select_overlapping_genes() {
+        let site = MethylationSite {
+            chromosome: 0,
+            location: 120,
+            strand: Strand::Sense,
+            context: Context::CG,
+            methylated: 0,
+            total: 0,
+            posterior: None,
+            status: None,
+            level: 0.0,
+            original: String::new(),
+        };
+        // Site in the downstream flank of the first gene and in the body of the second one
+        let (flank, body) = (gene(0, 100), gene(110, 300));
+        let genes = || vec![&flank, &body];
+
+        assert_eq!(OverlapPolicy::All.select(&site, genes()).len(), 2);
+        assert_eq!(OverlapPolicy::Closest.select(&site, genes()), [&body]);
+        assert_eq!(OverlapPolicy::PreferBody.select(&site, genes()), [&body]);
+        assert!(OverlapPolicy::Discard.select(&site, genes()).is_empty());
+        assert_eq!(OverlapPolicy::Discard.select(&site, vec![&flank]), [&flank]);
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::*;
+
+/// A sample of the pedigree, which may or may not have been sequenced
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub id: String,
+    pub generation: u32,
+    pub lineage: String,
+    /// File name of the methylome of the node within the methylome directory, `None` if the node was not sequenced
+    pub methylome: Option<String>,
+}
+
+/// Pedigree of the methylomes, rendered into AlphaBeta `nodelist.fn` and `edgelist.fn` files in every window directory.
+///
+/// The pedigree file is tab-separated with one node or edge per line, lines starting with `#` are comments:
+///
+/// ```text
+/// node    0_0    0    0    methylome_Col0_G0_All.txt
+/// node    3_2    3    2    -
+/// edge    0_0    1_2
+/// ```
+///
+/// Nodes are given by id, generation, lineage and the methylome file name (`-` if not sequenced), edges by the ids of the parent and the child.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pedigree {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<(String, String)>,
+}
+
+impl Pedigree {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let mut pedigree = Pedigree::default();
+        for (i, line) in lines_from_file(path)?.enumerate() {
+            let line = line?;
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').map(str::trim).collect();
+            match fields.as_slice() {
+                ["node", id, generation, lineage, methylome] => pedigree.nodes.push(Node {
+                    id: id.to_string(),
+                    generation: generation.parse()?,
+                    lineage: lineage.to_string(),
+                    methylome: Some(methylome.to_string()).filter(|m| m != "-"),
+                }),
+                ["edge", from, to] => pedigree.edges.push((from.to_string(), to.to_string())),
+                _ => {
+                    return Err(Error::Pedigree(format!(
+                        "line {} is neither a node nor an edge: {}",
+                        i + 1,
+                        line
+                    )))
+                }
+            }
+        }
+        pedigree.check()?;
+        Ok(pedigree)
+    }
+
+    /// Check that node ids are unique and that edges connect known nodes from an earlier to a later generation
+    fn check(&self) -> Result<()> {
+        let mut generations = HashMap::new();
+        for node in &self.nodes {
+            if generations
+                .insert(node.id.as_str(), node.generation)
+                .is_some()
+            {
+                return Err(Error::Pedigree(format!(
+                    "node {} is defined twice",
+                    node.id
+                )));
+            }
+        }
+        for (from, to) in &self.edges {
+            for id in [from, to] {
+                if !generations.contains_key(id.as_str()) {
+                    return Err(Error::Pedigree(format!(
+                        "edge {from} -> {to} refers to unknown node {id}"
+                    )));
+                }
+            }
+            if generations[from.as_str()] >= generations[to.as_str()] {
+                return Err(Error::Pedigree(format!(
+                    "edge {from} -> {to} does not lead to a later generation"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the methylome of every sequenced node is present in the methylome directory
+    pub fn validate(&self, methylome_files: &[(PathBuf, OsString)]) -> Result<()> {
+        let files: HashSet<&str> = methylome_files
+            .iter()
+            .filter_map(|(_, name)| name.to_str())
+            .collect();
+        for node in &self.nodes {
+            if let Some(methylome) = &node.methylome {
+                if !files.contains(methylome.as_str()) {
+                    return Err(Error::Pedigree(format!(
+                        "methylome {} of node {} is not in the methylome directory",
+                        methylome, node.id
+                    )));
+                }
+            }
+        }
+        let lineages: HashSet<&str> = self.nodes.iter().map(|n| n.lineage.as_str()).collect();
+        println!(
+            "Pedigree of {} nodes in {} lineages, {} of them sequenced",
+            self.nodes.len(),
+            lineages.len(),
+            self.nodes.iter().filter(|n| n.methylome.is_some()).count()
+        );
+        Ok(())
+    }
+
+    /// AlphaBeta node list for a window directory, pointing to the extracted methylomes within that directory
+    pub fn nodelist(&self, window_dir: &str) -> String {
+        let mut output = String::from("filename,node,gen,meth\n");
+        for node in &self.nodes {
+            let (filename, meth) = match &node.methylome {
+                Some(methylome) => (
+                    format!("{}/{}", window_dir, output_name(&OsString::from(methylome))),
+                    "Y",
+                ),
+                None => (String::from("-"), "N"),
+            };
+            output += &format!("{},{},{},{}\n", filename, node.id, node.generation, meth);
+        }
+        output
+    }
+
+    /// AlphaBeta edge list, the same for all window directories
+    pub fn edgelist(&self) -> String {
+        let mut output = String::from("from to\n");
+        for (from, to) in &self.edges {
+            output += &format!("{from} {to}\n");
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::Pedigree;
+    use crate::*;
+
+    const PEDIGREE: &str = "# type\tid\tgeneration\tlineage\tmethylome
+node\t0_0\t0\t0\tmethylome_G0.txt.gz
+node\t1_2\t1\t2\tmethylome_G1_L2.txt
+node\t2_2\t2\t2\t-
+edge\t0_0\t1_2
+edge\t1_2\t2_2
+";
+
+    fn pedigree(content: &str) -> Result<Pedigree> {
+        // Tests run in parallel, so every pedigree gets its own file
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let path = std::env::temp_dir().join(format!("extractor_pedigree_{id}.tsv"));
+        fs::write(&path, content).unwrap();
+        let pedigree = Pedigree::from_file(path.to_str().unwrap());
+        fs::remove_file(path).unwrap();
+        pedigree
+    }
+
+    #[test]
+    fn render_pedigree() {
+        let pedigree = pedigree(PEDIGREE).unwrap();
+        assert_eq!(
+            pedigree.nodelist("/data/windows/CG/gene/5"),
+            "filename,node,gen,meth
+/data/windows/CG/gene/5/methylome_G0.txt,0_0,0,Y
+/data/windows/CG/gene/5/methylome_G1_L2.txt,1_2,1,Y
+-,2_2,2,N
+"
+        );
+        assert_eq!(pedigree.edgelist(), "from to\n0_0 1_2\n1_2 2_2\n");
+    }
+
+    #[test]
+    fn validate_against_methylome_directory() {
+        let pedigree = pedigree(PEDIGREE).unwrap();
+        let file = |name: &str| (PathBuf::from(name), OsString::from(name));
+        assert!(pedigree
+            .validate(&[file("methylome_G0.txt.gz"), file("methylome_G1_L2.txt")])
+            .is_ok());
+        assert!(pedigree.validate(&[file("methylome_G0.txt.gz")]).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_pedigree() {
+        assert!(pedigree("edge\t0_0\t1_2\n").is_err());
+        assert!(pedigree("node\t0_0\t0\t0\t-\nnode\t0_0\t1\t0\t-\n").is_err());
+        assert!(pedigree("node\t0_0\tG0\t0\t-\n").is_err());
+        assert!(pedigree("0_0 1_2\n").is_err());
+        // Edges lead from parents to children of a later generation
+        assert!(pedigree("node\t0_0\t1\t0\t-\nnode\t1_0\t0\t0\t-\nedge\t0_0\t1_0\n").is_err());
+        assert!(pedigree("node\t0_0\t1\t0\t-\nnode\t1_0\t1\t0\t-\nedge\t0_0\t1_0\n").is_err());
+    }
+}
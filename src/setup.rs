@@ -1,96 +1,223 @@
 use std::fs;
 
+use itertools::Itertools;
+
 use crate::*;
 
-pub fn set_up_output_dir(max_gene_length: i32, args: Args) -> Result<()> {
-    fs::read_dir(&args.output_dir).map_err(|_| {
-        Error::File(
-            String::from("Output directory"),
-            String::from(&args.output_dir),
-        )
-    })?; // Throw error if base output dir does not exist
-         // Replace existing content of output dir
-    fs::remove_dir_all(&args.output_dir).unwrap();
-    fs::create_dir(&args.output_dir).unwrap();
-
-    let edgelist = String::from(
-        "from to
-0_0 1_2
-1_2 2_2
-2_2 3_2
-3_2 4_2
-4_2 5_2
-5_2 6_2
-6_2 7_2
-7_2 8_2
-8_2 9_2
-9_2 10_2
-10_2 11_2
-0_0 1_8
-1_8 2_8
-2_8 3_8
-3_8 4_8
-4_8 5_8
-5_8 6_8
-6_8 7_8
-7_8 8_8
-8_8 9_8
-9_8 10_8
-10_8 11_8",
-    );
+/// File marking a directory as output of an extractor run. Only directories containing it are ever deleted.
+pub const RUN_MANIFEST: &str = ".extractor_run";
 
-    let sides = vec![
-        ("upstream", args.cutoff),
-        ("gene", max_gene_length),
-        ("downstream", args.cutoff),
-    ];
-
-    for side in sides {
-        let max = if args.absolute { side.1 } else { 100 };
-        let side = side.0;
-
-        for window in (0..=max).step_by(args.window_step as usize) {
-            let nodelist = format!(
-                "filename,node,gen,meth
-/home/constantin/windows/{side}/{window}/methylome_Col0_G0_All.txt,0_0,0,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G1_L2_All.txt,1_2,1,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G1_L8_All.txt,1_8,1,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G2_L2_All.txt,2_2,2,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G2_L8_All.txt,2_8,2,Y
--,3_2,3,N
--,3_8,3,N
-/home/constantin/windows/{side}/{window}/methylome_Col0_G4_L2_All.txt,4_2,4,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G4_L8_All.txt,4_8,4,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G5_L2_All.txt,5_2,5,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G5_L8_All.txt,5_8,5,Y
--,6_2,6,N
--,6_8,6,N
--,7_2,7,N
--,7_8,7,N
-/home/constantin/windows/{side}/{window}/methylome_Col0_G8_L2_All.txt,8_2,8,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G8_L8_All.txt,8_8,8,Y
--,9_2,9,N
--,9_8,9,N
--,10_2,10,N
--,10_8,10,N
-/home/constantin/windows/{side}/{window}/methylome_Col0_G11_L2_All.txt,11_2,11,Y
-/home/constantin/windows/{side}/{window}/methylome_Col0_G11_L8_All.txt,11_8,11,Y
-"
-            );
+/// Window directories of a feature set as (context, region, window start), following the layout of each region
+fn window_dirs(set: &FeatureSet) -> Result<Vec<(Context, String, i32)>> {
+    let layout = &set.layout;
+    let mut windows = Vec::new();
+    for (context, region) in set
+        .args
+        .contexts()?
+        .into_iter()
+        .cartesian_product(layout.regions())
+    {
+        let region_layout = layout.get(region);
+        for window in 0..layout.count(region) {
+            windows.push((context, layout.name(region), region_layout.start(window)));
+        }
+    }
+    Ok(windows)
+}
+
+/// Check whether the output directory may be used, returning true if the content of a previous run has to be removed first.
+///
+/// Missing and empty directories are fine. Non-empty directories are only replaced with --force, and only if they contain the manifest of a previous run.
+fn check_output_dir(args: &Args) -> Result<bool> {
+    let Ok(mut entries) = fs::read_dir(&args.output_dir) else {
+        return Ok(false);
+    };
+    if entries.next().is_none() {
+        return Ok(false);
+    }
+    if !args.force {
+        return Err(Error::OutputDir(format!(
+            "{} is not empty, use --force to replace its content",
+            args.output_dir
+        )));
+    }
+    if !Path::new(&args.output_dir).join(RUN_MANIFEST).is_file() {
+        return Err(Error::OutputDir(format!(
+            "{} was not created by extractor (no {} file), refusing to delete it",
+            args.output_dir, RUN_MANIFEST
+        )));
+    }
+    Ok(true)
+}
+
+/// Create an empty output directory with one directory per feature set, context, region and window.
+/// If a pedigree is given, the AlphaBeta node and edge lists are written into every window directory.
+pub fn set_up_output_dir(
+    args: &Args,
+    sets: &[FeatureSet],
+    pedigree: Option<&Pedigree>,
+) -> Result<()> {
+    if check_output_dir(args)? {
+        fs::remove_dir_all(&args.output_dir)?;
+    }
+    fs::create_dir_all(&args.output_dir)?;
+    fs::write(
+        Path::new(&args.output_dir).join(RUN_MANIFEST),
+        format!("extractor {}\n{:?}\n", env!("CARGO_PKG_VERSION"), args),
+    )?;
 
-            let path = format!("{}/{}/{}", args.output_dir, side, window);
-            let window_dir = fs::read_dir(&path);
-
-            match window_dir {
-                Ok(_) => continue,
-                Err(_) => {
-                    fs::create_dir_all(&path).unwrap();
-                    fs::write(path.to_owned() + "/nodelist.fn", nodelist)
-                        .expect("Nodelist not writable at ");
-                    fs::write(path.to_owned() + "/edgelist.fn", &edgelist).expect("msg");
-                }
-            };
+    for set in sets {
+        let set_dir = &set.args.output_dir;
+        fs::create_dir_all(set_dir)?;
+        // Absolute path, so that the node lists point to the extracted methylomes regardless of the working directory
+        let output_dir = fs::canonicalize(set_dir)?;
+
+        for (context, side, window) in window_dirs(set)? {
+            let path = format!("{}/{}/{}/{}", set_dir, context, side, window);
+            if Path::new(&path).is_dir() {
+                continue;
+            }
+            fs::create_dir_all(&path)?;
+            if let Some(pedigree) = pedigree {
+                let window_dir = output_dir
+                    .join(context.to_string())
+                    .join(side)
+                    .join(window.to_string());
+                let window_dir = window_dir.to_str().ok_or_else(|| {
+                    Error::OutputDir(format!(
+                        "{} is not valid UTF-8 and cannot be written to the node list",
+                        window_dir.display()
+                    ))
+                })?;
+                fs::write(
+                    path.to_owned() + "/nodelist.fn",
+                    pedigree.nodelist(window_dir),
+                )?;
+                fs::write(path.to_owned() + "/edgelist.fn", pedigree.edgelist())?;
+            }
         }
     }
     Ok(())
 }
+
+/// Describe what a run would do without writing anything: the directory tree of every feature set, the number of windows and tiles and the methylome files
+pub fn plan(
+    args: &Args,
+    sets: &[FeatureSet],
+    tiling: Option<&Tiling>,
+    methylome_files: &[(PathBuf, OsString)],
+) -> Result<String> {
+    let replace = check_output_dir(args)?;
+
+    let mut plan = format!(
+        "Output directory: {}{}\n",
+        args.output_dir,
+        if replace {
+            " (content of the previous run will be replaced)"
+        } else {
+            ""
+        }
+    );
+    let mut total = 0;
+    for set in sets {
+        let windows = window_dirs(set)?;
+        let prefix = set
+            .label
+            .as_ref()
+            .map_or_else(String::new, |label| format!("{label}/"));
+        for ((context, side), group) in &windows.iter().group_by(|(c, s, _)| (*c, s.clone())) {
+            let starts: Vec<i32> = group.map(|(_, _, w)| *w).collect();
+            plan += &format!(
+                "  {}{}/{}/{{{}..={}}}: {} windows\n",
+                prefix,
+                context,
+                side,
+                starts.first().unwrap_or(&0),
+                starts.last().unwrap_or(&0),
+                starts.len()
+            );
+        }
+        total += windows.len();
+    }
+    plan += &format!("{} windows in total\n", total);
+    if let Some(tiling) = tiling {
+        let tiles: usize = tiling.contigs.iter().map(RegionLayout::count).sum();
+        plan += &format!(
+            "{} genome-wide tiles on {} contigs\n",
+            tiles,
+            tiling.contigs.len()
+        );
+    }
+    plan += &format!("{} methylome files:\n", methylome_files.len());
+    for (path, _) in methylome_files {
+        plan += &format!("  {}\n", path.display());
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_dir(name: &str) -> Args {
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Args {
+            output_dir: path.to_string_lossy().into_owned(),
+            window_size: 50,
+            window_step: 50,
+            cutoff: 100,
+            ..Default::default()
+        }
+    }
+
+    /// The feature set of a single gene of 100 bp
+    fn set(args: &Args) -> Vec<FeatureSet> {
+        let gene = Gene {
+            chromosome: 0,
+            start: 0,
+            end: 100,
+            name: String::from("AT1G01010"),
+            strand: Strand::Sense,
+            flanks: None,
+            transcript: None,
+        };
+        vec![FeatureSet::new(None, args.clone(), vec![gene], 1).unwrap()]
+    }
+
+    #[test]
+    fn refuse_foreign_directories() {
+        let mut args = output_dir("extractor_setup_foreign");
+        fs::write(Path::new(&args.output_dir).join("results.txt"), "precious").unwrap();
+        assert!(set_up_output_dir(&args, &set(&args), None).is_err());
+
+        args.force = true;
+        assert!(set_up_output_dir(&args, &set(&args), None).is_err());
+        assert!(Path::new(&args.output_dir).join("results.txt").exists());
+    }
+
+    #[test]
+    fn replace_previous_runs() {
+        let mut args = output_dir("extractor_setup_previous");
+        set_up_output_dir(&args, &set(&args), None).unwrap();
+        assert!(Path::new(&args.output_dir).join("CG/gene/50").is_dir());
+        assert!(set_up_output_dir(&args, &set(&args), None).is_err());
+
+        args.force = true;
+        set_up_output_dir(&args, &set(&args), None).unwrap();
+        assert!(Path::new(&args.output_dir).join(RUN_MANIFEST).is_file());
+    }
+
+    #[test]
+    fn plan_without_writing() {
+        let mut args = output_dir("extractor_setup_plan");
+        args.output_dir += "/missing";
+        let files = [(PathBuf::from("/data/G0.txt"), OsString::from("G0.txt"))];
+        let plan = plan(&args, &set(&args), None, &files).unwrap();
+        assert!(plan.contains("  CG/gene/{0..=50}: 2 windows\n"));
+        assert!(plan.contains("6 windows in total\n"));
+        assert!(plan.contains("  /data/G0.txt\n"));
+        assert!(!Path::new(&args.output_dir).exists());
+    }
+}
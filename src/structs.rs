@@ -1,37 +1,86 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 use itertools::Itertools;
 
-use crate::error;
+use crate::{
+    contigs::{ContigId, Contigs},
+    error::{self, Error},
+    flanks::Flanks,
+    transcripts::Transcript,
+};
 
 pub type Result<T> = std::result::Result<T, error::Error>;
 
-#[derive(Clone, PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
 pub enum Strand {
     Sense,
     Antisense,
 }
 
-#[derive(Debug, Clone)]
+impl Strand {
+    /// Parse the strand symbol of an annotation or methylome file. Everything except `+` is considered the antisense strand.
+    pub fn from_symbol(symbol: &str, invert_strand: bool) -> Self {
+        // XOR: if the strand is + and we don't want to invert it, or if the strand is - and we do want to invert it -> Sense
+        if (symbol == "+") ^ invert_strand {
+            Strand::Sense
+        } else {
+            Strand::Antisense
+        }
+    }
+}
+
+/// Sequence context of a methylation site, with H being any base but G
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Context {
+    CG,
+    CHG,
+    CHH,
+}
+
+/// Methylation status of a site as called by methimpute
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Unmethylated,
+    Intermediate,
+    Methylated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Region {
     Upstream,
     Gene,
     Downstream,
+    /// Exonic part of the gene body, on the coordinate of the spliced transcript
+    Spliced,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Gene {
-    pub chromosome: u8,
+    /// Id of the contig in the `Contigs` dictionary
+    pub chromosome: ContigId,
     pub start: i32,
     pub end: i32,
     pub name: String,
     pub strand: Strand,
+    /// Flanks truncated at the neighbouring genes, `None` if the full upstream and downstream lengths apply
+    pub flanks: Option<Flanks>,
+    /// Exons and coding sequence of the gene, if resolved from the annotation with --transcripts
+    pub transcript: Option<Transcript>,
 }
 
+/// Contig, start, end and strand of a gene, which identify it in the gene-level outputs. Names are not unique, e.g. placeholder names of BED files or the same ID on several contigs.
+pub type GeneKey = (ContigId, i32, i32, Strand);
+
+/// Genes of a contig, split by strand. Once sorted, the genes of each strand form an interval index: sorted by start, with the running maximum of the gene ends, so that all genes overlapping a position are found by a binary search and a backwards scan, see `MethylationSite::find_genes`.
 #[derive(Clone)]
 pub struct GenesByStrand {
     pub sense: Vec<Gene>,
     pub antisense: Vec<Gene>,
+    /// Maximum end of all sense genes up to and including the same index
+    sense_max_end: Vec<i32>,
+    /// Maximum end of all antisense genes up to and including the same index
+    antisense_max_end: Vec<i32>,
 }
 
 impl GenesByStrand {
@@ -39,6 +88,8 @@ impl GenesByStrand {
         GenesByStrand {
             sense: Vec::new(),
             antisense: Vec::new(),
+            sense_max_end: Vec::new(),
+            antisense_max_end: Vec::new(),
         }
     }
 
@@ -49,28 +100,113 @@ impl GenesByStrand {
         }
     }
 
+    /// Sort the genes by start and build the running maximum of their ends. Needs to be called before querying overlapping genes.
     pub fn sort(&mut self) {
-        self.sense.sort_by(|a, b| a.start.cmp(&b.start));
-        self.antisense.sort_by(|a, b| a.start.cmp(&b.start));
+        let running_max = |genes: &[Gene]| {
+            genes
+                .iter()
+                .scan(i32::MIN, |max, g| {
+                    *max = (*max).max(g.end);
+                    Some(*max)
+                })
+                .collect()
+        };
+        self.sense.sort_by_key(|g| g.start);
+        self.antisense.sort_by_key(|g| g.start);
+        self.sense_max_end = running_max(&self.sense);
+        self.antisense_max_end = running_max(&self.antisense);
+    }
+
+    /// All genes on a strand whose region, including `upstream` and `downstream` bases of flanks, contains a location. Ordered by gene start.
+    pub fn overlapping(
+        &self,
+        strand: &Strand,
+        location: i32,
+        upstream: i32,
+        downstream: i32,
+    ) -> Vec<&Gene> {
+        let cutoff = upstream.max(downstream);
+        let (genes, max_end) = match strand {
+            Strand::Sense => (&self.sense, &self.sense_max_end),
+            Strand::Antisense => (&self.antisense, &self.antisense_max_end),
+        };
+        // Genes starting after the location can not contain it
+        let candidates = genes.partition_point(|g| g.start - cutoff <= location);
+        // Scanning backwards, no earlier gene reaches the location once the running maximum of the ends falls below it
+        let mut overlapping: Vec<&Gene> = (0..candidates)
+            .rev()
+            .take_while(|&i| max_end[i] + cutoff >= location)
+            .map(|i| &genes[i])
+            .filter(|g| {
+                let (from, to) = g.bounds(upstream, downstream);
+                from <= location && location <= to
+            })
+            .collect();
+        overlapping.reverse();
+        overlapping
     }
 }
 
 impl Gene {
-    pub fn from_annotation_file_line(s: &str, invert_strand: bool) -> Option<Self> {
-        s.split('\t')
-            .collect_tuple()
-            .map(|(chromosome, start, end, name, _, strand)| Gene {
-                chromosome: chromosome.parse::<u8>().unwrap(),
-                start: start.parse::<i32>().unwrap(),
-                end: end.parse::<i32>().unwrap(),
-                name: String::from(name),
-                strand: if (strand == "+") ^ invert_strand {
-                    // XOR: if the strand is + and we don't want to invert it, or if the strand is - and we do want to invert it -> Sense
-                    Strand::Sense
-                } else {
-                    Strand::Antisense
-                },
-            })
+    pub fn key(&self) -> GeneKey {
+        (self.chromosome, self.start, self.end, self.strand.clone())
+    }
+
+    /// Length of the upstream flank, the upstream length unless truncated at a neighbouring gene
+    pub fn upstream(&self, length: i32) -> i32 {
+        self.flanks.map_or(length, |f| f.upstream.min(length))
+    }
+
+    /// Length of the downstream flank, the downstream length unless truncated at a neighbouring gene
+    pub fn downstream(&self, length: i32) -> i32 {
+        self.flanks.map_or(length, |f| f.downstream.min(length))
+    }
+
+    /// First and last base of the region of the gene, including its upstream and downstream flanks
+    pub fn bounds(&self, upstream: i32, downstream: i32) -> (i32, i32) {
+        match self.strand {
+            Strand::Sense => (
+                self.start - self.upstream(upstream),
+                self.end + self.downstream(downstream),
+            ),
+            Strand::Antisense => (
+                self.start - self.downstream(downstream),
+                self.end + self.upstream(upstream),
+            ),
+        }
+    }
+
+    /// Create a new gene from a line of a six-column annotation file (`chromosome start end name score strand`).
+    /// The blocks of twelve-column BED12 lines are kept as the transcript model of the gene.
+    /// Lines with a different number of fields or unparsable coordinates yield `None`.
+    /// The contig of the gene is added to the dictionary if it is not known yet.
+    pub fn from_annotation_file_line(
+        s: &str,
+        contigs: &mut Contigs,
+        invert_strand: bool,
+    ) -> Option<Self> {
+        let fields: Vec<&str> = s.split('\t').collect();
+        let (chromosome, start, end, name, _, strand) = match fields.len() {
+            6 | 12 => fields[..6].iter().copied().collect_tuple()?,
+            _ => return None,
+        };
+        let start = start.parse::<i32>().ok()?;
+        let end = end.parse::<i32>().ok()?;
+        let transcript = match fields[..] {
+            [.., thick_start, thick_end, _, _, sizes, starts] if fields.len() == 12 => Some(
+                Transcript::from_bed_blocks(start, thick_start, thick_end, sizes, starts)?,
+            ),
+            _ => None,
+        };
+        Some(Gene {
+            chromosome: contigs.intern(chromosome),
+            start,
+            end,
+            name: String::from(name),
+            strand: Strand::from_symbol(strand, invert_strand),
+            flanks: None,
+            transcript,
+        })
     }
 }
 
@@ -84,6 +220,17 @@ impl Display for Gene {
     }
 }
 
+impl Display for Region {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Region::Upstream => write!(f, "upstream"),
+            Region::Gene => write!(f, "gene"),
+            Region::Downstream => write!(f, "downstream"),
+            Region::Spliced => write!(f, "spliced"),
+        }
+    }
+}
+
 impl Display for Strand {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -92,3 +239,49 @@ impl Display for Strand {
         }
     }
 }
+
+impl FromStr for Context {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "CG" | "CpG" => Ok(Context::CG),
+            "CHG" => Ok(Context::CHG),
+            "CHH" => Ok(Context::CHH),
+            _ => Err(Error::Context(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Context::CG => write!(f, "CG"),
+            Context::CHG => write!(f, "CHG"),
+            Context::CHH => write!(f, "CHH"),
+        }
+    }
+}
+
+impl FromStr for Status {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "U" | "Unmethylated" => Ok(Status::Unmethylated),
+            "I" | "Intermediate" => Ok(Status::Intermediate),
+            "M" | "Methylated" => Ok(Status::Methylated),
+            _ => Err(Error::Status(s.to_owned())),
+        }
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Unmethylated => write!(f, "U"),
+            Status::Intermediate => write!(f, "I"),
+            Status::Methylated => write!(f, "M"),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::*;
+
+/// Header of the tidy summary table written for every run, one row per methylome, context, region and window
+pub const SUMMARY_HEADER: &str =
+    "sample\tcontext\tregion\twindow\tsites\tcoverage\tmean_level\tweighted_level\tfraction_methylated\n";
+
+/// Header of the long-format metagene profile table covering all methylomes of a run, with one continuous window index over upstream, gene and downstream
+pub const PROFILE_HEADER: &str =
+    "sample\tcontext\tregion\twindow_index\twindow_start_label\tsites\tcoverage\tweighted_level\tmean_level\n";
+
+/// Header of the gene-level table, one row per methylome, context and gene
+pub const GENES_HEADER: &str =
+    "sample\tcontext\tgene\tcontig\tstart\tend\tstrand\tsites\tcovered_sites\tupstream_weighted_level\tbody_weighted_level\tdownstream_weighted_level\tfraction_methylated\n";
+
+/// Methylation level statistics of a set of sites, e.g. all sites of a window
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Number of sites
+    pub sites: usize,
+    /// Sum of methylated reads over all sites
+    pub methylated: u64,
+    /// Sum of all reads over all sites
+    pub coverage: u64,
+    /// Number of sites with at least one read
+    pub covered: usize,
+    level_sum: f64,
+    /// Number of sites with a known methylation level
+    leveled: usize,
+    /// Number of sites with status methylated
+    methylated_sites: usize,
+    /// Number of sites with a known status
+    called: usize,
+}
+
+impl Summary {
+    pub fn add(&mut self, site: &MethylationSite) {
+        self.sites += 1;
+        self.methylated += site.methylated as u64;
+        self.coverage += site.total as u64;
+        if site.total > 0 {
+            self.covered += 1;
+        }
+        if !site.level.is_nan() {
+            self.level_sum += site.level as f64;
+            self.leveled += 1;
+        }
+        if let Some(status) = site.status {
+            self.called += 1;
+            if status == Status::Methylated {
+                self.methylated_sites += 1;
+            }
+        }
+    }
+
+    /// Add the sites of another summary, e.g. of the same window in another chunk of a methylome
+    pub fn merge(&mut self, other: &Summary) {
+        self.sites += other.sites;
+        self.methylated += other.methylated;
+        self.coverage += other.coverage;
+        self.covered += other.covered;
+        self.level_sum += other.level_sum;
+        self.leveled += other.leveled;
+        self.methylated_sites += other.methylated_sites;
+        self.called += other.called;
+    }
+
+    /// Mean of the methylation levels (`rc.meth.lvl`) of all sites
+    pub fn mean_level(&self) -> f64 {
+        self.level_sum / self.leveled as f64
+    }
+
+    /// Pooled methylation level, the sum of methylated reads divided by the sum of all reads. Sites with a higher coverage weigh more.
+    pub fn weighted_level(&self) -> f64 {
+        self.methylated as f64 / self.coverage as f64
+    }
+
+    /// Fraction of the sites with a known status that were called methylated (status `M`)
+    pub fn fraction_methylated(&self) -> f64 {
+        self.methylated_sites as f64 / self.called as f64
+    }
+}
+
+impl<'a> FromIterator<&'a MethylationSite> for Summary {
+    fn from_iter<T: IntoIterator<Item = &'a MethylationSite>>(iter: T) -> Self {
+        let mut summary = Summary::default();
+        iter.into_iter().for_each(|site| summary.add(site));
+        summary
+    }
+}
+
+/// Tab-separated `sites coverage mean_level weighted_level fraction_methylated`. Undefined levels, e.g. of empty windows, are written as `NA`.
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.sites,
+            self.coverage,
+            Level(self.mean_level()),
+            Level(self.weighted_level()),
+            Level(self.fraction_methylated())
+        )
+    }
+}
+
+/// Summaries of the upstream flank, body and downstream flank of every gene that received sites
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeneSummaries {
+    /// Summaries by gene location. Genes with the same location and strand receive the same sites and share them.
+    genes: HashMap<GeneKey, [Summary; 3]>,
+}
+
+impl GeneSummaries {
+    pub fn add(&mut self, gene: &Gene, region: Region, site: &MethylationSite) {
+        let summaries = self.genes.entry(gene.key()).or_default();
+        let index = match region {
+            Region::Upstream => 0,
+            Region::Gene | Region::Spliced => 1,
+            Region::Downstream => 2,
+        };
+        summaries[index].add(site);
+    }
+
+    pub fn merge(&mut self, other: GeneSummaries) {
+        for (key, summaries) in other.genes {
+            let merged = self.genes.entry(key).or_default();
+            for (merged, summary) in merged.iter_mut().zip(&summaries) {
+                merged.merge(summary);
+            }
+        }
+    }
+
+    /// Swap the summaries of the upstream and downstream flanks, to switch from 5' to 3'
+    pub fn inverse(mut self) -> Self {
+        self.genes.values_mut().for_each(|s| s.swap(0, 2));
+        self
+    }
+
+    /// Rows of the gene-level table for all genes, including those without any sites, see `GENES_HEADER`.
+    /// The sites, covered sites and fraction of methylated sites are those of the gene body.
+    pub fn rows(
+        &self,
+        sample: &str,
+        context: Context,
+        genes: &[Gene],
+        contigs: &Contigs,
+    ) -> String {
+        let empty = Default::default();
+        let mut output = String::new();
+        for gene in genes {
+            let [upstream, body, downstream] = self.genes.get(&gene.key()).unwrap_or(&empty);
+            output.push_str(&format!(
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                sample,
+                context,
+                gene.name,
+                contigs.name(gene.chromosome),
+                gene.start,
+                gene.end,
+                gene.strand,
+                body.sites,
+                body.covered,
+                Level(upstream.weighted_level()),
+                Level(body.weighted_level()),
+                Level(downstream.weighted_level()),
+                Level(body.fraction_methylated())
+            ));
+        }
+        output
+    }
+}
+
+/// A methylation level formatted to four decimals, or `NA` if undefined
+pub struct Level(pub f64);
+
+impl Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_nan() {
+            write!(f, "NA")
+        } else {
+            write!(f, "{:.4}", self.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Summary;
+    use crate::*;
+
+    fn site(methylated: u32, total: u32, status: Option<Status>) -> MethylationSite {
+        MethylationSite {
+            chromosome: 0,
+            location: 0,
+            strand: Strand::Sense,
+            context: Context::CG,
+            methylated,
+            total,
+            posterior: None,
+            status,
+            level: methylated as f32 / total as f32,
+            original: String::new(),
+        }
+    }
+
+    #[test]
+    fn summarize_sites() {
+        let sites = [
+            site(1, 1, Some(Status::Methylated)),
+            site(0, 9, Some(Status::Unmethylated)),
+            site(0, 0, None),
+        ];
+        let summary: Summary = sites.iter().collect();
+        assert_eq!(summary.sites, 3);
+        assert_eq!(summary.coverage, 10);
+        assert_eq!(summary.covered, 2);
+        assert_eq!(summary.methylated, 1);
+        assert_eq!(summary.mean_level(), 0.5);
+        assert_eq!(summary.weighted_level(), 0.1);
+        assert_eq!(summary.fraction_methylated(), 0.5);
+        assert_eq!(summary.to_string(), "3\t10\t0.5000\t0.1000\t0.5000");
+    }
+
+    #[test]
+    fn summarize_empty_window() {
+        let summary = Summary::default();
+        assert_eq!(summary.to_string(), "0\t0\tNA\tNA\tNA");
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use crate::*;
+
+/// Position of the sweep on the genes of one strand
+#[derive(Default)]
+struct StrandSweep<'long> {
+    /// Contig of the last site, `None` before the first site on the strand
+    contig: Option<ContigId>,
+    /// Location of the last site
+    location: i32,
+    /// Index of the next gene on the contig that has not entered the sweep yet
+    next: usize,
+    /// Genes whose region may contain the current or a later site, ordered by gene start
+    active: Vec<&'long Gene>,
+    /// Contigs that were left already
+    visited: HashSet<ContigId>,
+}
+
+/// Finds the genes of a feature set that sites belong to by walking the sorted sites and the sorted genes of each contig and strand together.
+/// Genes enter the sweep once the sites reach their upstream region and leave it once the sites are past their downstream region, so each gene is visited once per methylome instead of once per site.
+///
+/// Methylome files are almost always sorted by contig and position. Sites of an unsorted file are detected by a contig that was left already or a position before the last one, and from then on found with the interval index of the genes, see `MethylationSite::find_genes`.
+pub struct Sweep<'long> {
+    genome: &'long [GenesByStrand],
+    upstream: i32,
+    downstream: i32,
+    sense: StrandSweep<'long>,
+    antisense: StrandSweep<'long>,
+    /// Whether the sites were found to be unsorted, which switches to the indexed lookup
+    unsorted: bool,
+}
+
+impl<'long> Sweep<'long> {
+    pub fn new(genome: &'long [GenesByStrand], upstream: i32, downstream: i32) -> Self {
+        Sweep {
+            genome,
+            upstream,
+            downstream,
+            sense: StrandSweep::default(),
+            antisense: StrandSweep::default(),
+            unsorted: false,
+        }
+    }
+
+    pub fn is_unsorted(&self) -> bool {
+        self.unsorted
+    }
+
+    /// All genes whose region, including the flanks, contains a site, ordered by gene start. The same genes as `MethylationSite::find_genes`.
+    pub fn find_genes(&mut self, site: &MethylationSite) -> Vec<&'long Gene> {
+        if !self.unsorted && !self.advance(site) {
+            self.unsorted = true;
+        }
+        if self.unsorted {
+            return site.find_genes(self.genome, self.upstream, self.downstream);
+        }
+        let (upstream, downstream) = (self.upstream, self.downstream);
+        let state = match site.strand {
+            Strand::Sense => &self.sense,
+            Strand::Antisense => &self.antisense,
+        };
+        state
+            .active
+            .iter()
+            .filter(|g| {
+                let (from, to) = g.bounds(upstream, downstream);
+                from <= site.location && site.location <= to
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Move the sweep of the strand of a site to its position. Returns false if the site is before the sweep, so the sites are not sorted.
+    fn advance(&mut self, site: &MethylationSite) -> bool {
+        let cutoff = self.upstream.max(self.downstream);
+        let (state, genes) = match site.strand {
+            Strand::Sense => (
+                &mut self.sense,
+                self.genome.get(site.chromosome).map(|g| &g.sense),
+            ),
+            Strand::Antisense => (
+                &mut self.antisense,
+                self.genome.get(site.chromosome).map(|g| &g.antisense),
+            ),
+        };
+        if state.contig != Some(site.chromosome) {
+            if state.visited.contains(&site.chromosome) {
+                return false;
+            }
+            if let Some(contig) = state.contig {
+                state.visited.insert(contig);
+            }
+            state.contig = Some(site.chromosome);
+            state.next = 0;
+            state.active.clear();
+        } else if site.location < state.location {
+            return false;
+        }
+        state.location = site.location;
+
+        let genes: &'long [Gene] = genes.map_or(&[], Vec::as_slice);
+        // Genes enter once the site reaches the start of their region, in the order of their start
+        while state.next < genes.len() && genes[state.next].start - cutoff <= site.location {
+            state.active.push(&genes[state.next]);
+            state.next += 1;
+        }
+        // Genes leave once the site is past the end of their region, later sites can not be in it either
+        state.active.retain(|g| g.end + cutoff >= site.location);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gene(start: i32, end: i32, strand: Strand) -> Gene {
+        Gene {
+            chromosome: 0,
+            start,
+            end,
+            name: format!("{start}-{end}"),
+            strand,
+            flanks: None,
+            transcript: None,
+        }
+    }
+
+    fn site(chromosome: ContigId, location: i32, strand: Strand) -> MethylationSite {
+        MethylationSite {
+            chromosome,
+            location,
+            strand,
+            context: Context::CG,
+            methylated: 1,
+            total: 2,
+            posterior: None,
+            status: None,
+            level: 0.5,
+            original: String::new(),
+        }
+    }
+
+    fn genome() -> Vec<GenesByStrand> {
+        let mut genes = GenesByStrand::new();
+        for (start, end) in [(100, 1000), (150, 200), (400, 500), (1500, 1600)] {
+            genes.insert(gene(start, end, Strand::Sense));
+        }
+        genes.insert(gene(300, 400, Strand::Antisense));
+        genes.sort();
+        vec![genes.clone(), genes]
+    }
+
+    #[test]
+    fn sweep_sorted_sites() {
+        let genome = genome();
+        let mut sweep = Sweep::new(&genome, 50, 100);
+        let mut sites = Vec::new();
+        for contig in 0..2 {
+            for location in (0..1800).step_by(7) {
+                sites.push(site(contig, location, Strand::Sense));
+                sites.push(site(contig, location, Strand::Antisense));
+            }
+        }
+        for site in &sites {
+            assert_eq!(
+                sweep.find_genes(site),
+                site.find_genes(&genome, 50, 100),
+                "{site:?}"
+            );
+        }
+        assert!(!sweep.is_unsorted());
+    }
+
+    #[test]
+    fn sweep_falls_back_on_unsorted_sites() {
+        let genome = genome();
+        let mut sweep = Sweep::new(&genome, 50, 100);
+        assert_eq!(sweep.find_genes(&site(0, 180, Strand::Sense)).len(), 2);
+        assert!(!sweep.is_unsorted());
+        // The gene at 400 left the sweep, the site before the last one is only found by the indexed lookup
+        assert_eq!(sweep.find_genes(&site(0, 1050, Strand::Sense)).len(), 1);
+        assert_eq!(sweep.find_genes(&site(0, 450, Strand::Sense)).len(), 2);
+        assert!(sweep.is_unsorted());
+
+        // Returning to a contig that was left is unsorted as well
+        let mut sweep = Sweep::new(&genome, 50, 100);
+        sweep.find_genes(&site(0, 180, Strand::Sense));
+        sweep.find_genes(&site(1, 180, Strand::Sense));
+        assert_eq!(sweep.find_genes(&site(0, 450, Strand::Sense)).len(), 2);
+        assert!(sweep.is_unsorted());
+    }
+}
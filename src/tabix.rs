@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+
+use flate2::read::{GzDecoder, MultiGzDecoder};
+use itertools::Itertools;
+
+use crate::*;
+
+/// Binning scheme of tabix indices: the smallest bins span 2^14 bases, with 5 levels of larger bins above them
+const TBI_MIN_SHIFT: u32 = 14;
+const TBI_DEPTH: u32 = 5;
+
+/// Chunk of a bgzip file as a range of virtual offsets, the offset of a BGZF block in the file shifted by 16 bits plus the offset within the decompressed block
+type Chunk = (u64, u64);
+
+/// Chunks of the records of one sequence, by bin
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Reference {
+    bins: HashMap<u32, Vec<Chunk>>,
+    /// Smallest virtual offset of the records overlapping each window of 2^14 bases, only part of tabix indices
+    linear: Vec<u64>,
+}
+
+/// Tabix (`.tbi`) or CSI (`.csi`) index of a bgzip-compressed methylome, see the [tabix](https://samtools.github.io/hts-specs/tabix.pdf) and [CSI](https://samtools.github.io/hts-specs/CSIv1.pdf) specifications.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Index {
+    min_shift: u32,
+    depth: u32,
+    /// Names of the indexed sequences, in the order of the file
+    pub names: Vec<String>,
+    references: Vec<Reference>,
+}
+
+/// Little-endian reader of the fields of an index
+struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() < length {
+            return Err(Error::Index(String::from("unexpected end of the index")));
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A count or length, stored as a signed 32 bit integer
+    fn count(&mut self) -> Result<usize> {
+        let count = i32::from_le_bytes(self.bytes(4)?.try_into().unwrap());
+        usize::try_from(count).map_err(|_| Error::Index(format!("negative count {count}")))
+    }
+
+    /// The tabix header shared by both index types, returning the sequence names
+    fn names(&mut self) -> Result<Vec<String>> {
+        // Format, columns of the sequence, start and end, meta character and number of skipped lines
+        self.bytes(6 * 4)?;
+        let length = self.count()?;
+        Ok(self
+            .bytes(length)?
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| String::from_utf8_lossy(name).into_owned())
+            .collect())
+    }
+}
+
+impl Index {
+    /// The index next to a methylome file, `<methylome>.tbi` or `<methylome>.csi`, if there is one
+    pub fn find(path: &Path) -> Option<PathBuf> {
+        ["tbi", "csi"].into_iter().find_map(|extension| {
+            let mut index = path.as_os_str().to_owned();
+            index.push(".");
+            index.push(extension);
+            let index = PathBuf::from(index);
+            index.exists().then_some(index)
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let mut data = Vec::new();
+        MultiGzDecoder::new(File::open(path)?).read_to_end(&mut data)?;
+        Index::from_bytes(&data)
+    }
+
+    /// Parse a decompressed tabix or CSI index
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        let mut fields = Fields { data };
+        let (min_shift, depth, names, csi) = match fields.bytes(4)? {
+            b"TBI\x01" => {
+                let count = fields.count()?;
+                let names = fields.names()?;
+                if names.len() != count {
+                    return Err(Error::Index(format!(
+                        "expected {count} sequence names, found {}",
+                        names.len()
+                    )));
+                }
+                (TBI_MIN_SHIFT, TBI_DEPTH, names, false)
+            }
+            b"CSI\x01" => {
+                let min_shift = fields.u32()?;
+                let depth = fields.u32()?;
+                let length = fields.count()?;
+                // The sequence names are part of the auxiliary data written by tabix
+                let mut aux = Fields {
+                    data: fields.bytes(length)?,
+                };
+                let names = aux.names().map_err(|_| {
+                    Error::Index(String::from("CSI index without tabix sequence names"))
+                })?;
+                (min_shift, depth, names, true)
+            }
+            _ => {
+                return Err(Error::Index(String::from(
+                    "neither a tabix nor a CSI index",
+                )))
+            }
+        };
+
+        let count = if csi { fields.count()? } else { names.len() };
+        let mut references = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mut reference = Reference::default();
+            for _ in 0..fields.count()? {
+                let bin = fields.u32()?;
+                if csi {
+                    // Smallest virtual offset of the records in the bin, the CSI counterpart of the linear index
+                    fields.u64()?;
+                }
+                let chunks = (0..fields.count()?)
+                    .map(|_| Ok((fields.u64()?, fields.u64()?)))
+                    .collect::<Result<_>>()?;
+                reference.bins.insert(bin, chunks);
+            }
+            if !csi {
+                reference.linear = (0..fields.count()?)
+                    .map(|_| fields.u64())
+                    .collect::<Result<_>>()?;
+            }
+            references.push(reference);
+        }
+        Ok(Index {
+            min_shift,
+            depth,
+            names,
+            references,
+        })
+    }
+
+    /// Bins that may hold records overlapping the 0-based, half-open interval from `start` to `end`, from the largest to the smallest bins
+    fn bins(&self, start: u64, end: u64) -> Vec<u32> {
+        let mut bins = Vec::new();
+        let mut shift = self.min_shift + 3 * self.depth;
+        let mut first = 0;
+        for level in 0..=self.depth {
+            let (from, to) = (first + (start >> shift), first + ((end - 1) >> shift));
+            bins.extend((from..=to).map(|bin| bin as u32));
+            shift -= 3;
+            first += 1 << (3 * level);
+        }
+        bins
+    }
+
+    /// Chunks of the records of a sequence that may overlap the 0-based, half-open interval from `start` to `end`
+    fn chunks(&self, reference: usize, start: u64, end: u64) -> Vec<Chunk> {
+        let Some(reference) = self.references.get(reference) else {
+            return Vec::new();
+        };
+        // Records ending before the interval start before this offset
+        let min_offset = reference
+            .linear
+            .get((start >> TBI_MIN_SHIFT) as usize)
+            .copied()
+            .unwrap_or(0);
+        self.bins(start, end)
+            .iter()
+            .filter_map(|bin| reference.bins.get(bin))
+            .flatten()
+            .filter(|chunk| chunk.1 > min_offset)
+            .copied()
+            .collect()
+    }
+}
+
+/// Regions of the features of all sets including their flanks by contig id, sorted and merged. Only these parts of indexed methylome files are read.
+pub fn feature_regions(sets: &[FeatureSet], contig_count: usize) -> Vec<Vec<(i32, i32)>> {
+    let mut regions = vec![Vec::new(); contig_count];
+    for set in sets {
+        let (upstream, downstream) = (set.layout.upstream.length, set.layout.downstream.length);
+        for (contig, genes) in set.genome.iter().enumerate() {
+            for gene in genes.sense.iter().chain(&genes.antisense) {
+                regions[contig].push(gene.bounds(upstream, downstream));
+            }
+        }
+    }
+    for contig in regions.iter_mut() {
+        contig.sort_unstable();
+        *contig = contig
+            .iter()
+            .copied()
+            .coalesce(|a, b| {
+                if b.0 <= a.1 + 1 {
+                    Ok((a.0, a.1.max(b.1)))
+                } else {
+                    Err((a, b))
+                }
+            })
+            .collect();
+    }
+    regions
+}
+
+/// Open the parts of a bgzip-compressed methylome file that hold the sites within the regions of the features, if it has a tabix or CSI index.
+/// The sites are read in the order of the file, and every site is read once, even if the regions of several features contain it.
+/// Returns `None` for files without an index, which are read completely.
+pub fn open_indexed(
+    path: &Path,
+    regions: &[Vec<(i32, i32)>],
+    contigs: &Contigs,
+) -> Result<Option<MethylomeReader>> {
+    let Some(index_path) = Index::find(path) else {
+        return Ok(None);
+    };
+    let index = Index::from_file(&index_path)?;
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for (reference, name) in index.names.iter().enumerate() {
+        let Some(regions) = contigs.get(name).and_then(|id| regions.get(id)) else {
+            continue;
+        };
+        let mut found: Vec<Chunk> = regions
+            .iter()
+            // Site positions are 1-based, the queried intervals 0-based. One more base on each side does not change the result, as sites outside the regions are not placed.
+            .flat_map(|(from, to)| {
+                index.chunks(
+                    reference,
+                    (*from - 2).max(0) as u64,
+                    (*to + 1).max(1) as u64,
+                )
+            })
+            .collect();
+        found.sort_unstable();
+        for chunk in found {
+            match chunks.last_mut() {
+                Some(last) if chunk.0 <= last.1 => last.1 = last.1.max(chunk.1),
+                _ => chunks.push(chunk),
+            }
+        }
+    }
+    let reader = IndexedReader {
+        file: File::open(path)?,
+        chunks: chunks.into_iter(),
+        block: Vec::new(),
+        block_start: 0,
+        block_end: 0,
+        position: 0,
+        end: 0,
+    };
+    Ok(Some(Box::new(io::BufReader::new(reader))))
+}
+
+/// Reader of the decompressed bytes of a list of chunks of a bgzip file
+struct IndexedReader {
+    file: File,
+    /// Chunks that are not read yet
+    chunks: std::vec::IntoIter<Chunk>,
+    /// The current decompressed BGZF block
+    block: Vec<u8>,
+    /// Offsets of the current block and of the block after it in the file
+    block_start: u64,
+    block_end: u64,
+    /// Read position within the current block
+    position: usize,
+    /// Virtual offset the current chunk ends at
+    end: u64,
+}
+
+impl IndexedReader {
+    /// Read and decompress the BGZF block at an offset of the file. Returns false at the end of the file.
+    fn load_block(&mut self, offset: u64) -> io::Result<bool> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        self.file.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; 12];
+        match self.file.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?,
+        }
+        // Gzip members with extra fields, holding the size of the block in the `BC` subfield
+        if header[..2] != GZIP_MAGIC || header[3] & 4 == 0 {
+            return Err(invalid("indexed methylome is not bgzip-compressed"));
+        }
+        let mut extra = vec![0; u16::from_le_bytes([header[10], header[11]]) as usize];
+        self.file.read_exact(&mut extra)?;
+        let mut size = None;
+        let mut subfields = extra.as_slice();
+        while subfields.len() >= 4 {
+            let length = u16::from_le_bytes([subfields[2], subfields[3]]) as usize;
+            if subfields[..2] == *b"BC" && length == 2 && subfields.len() >= 6 {
+                size = Some(u16::from_le_bytes([subfields[4], subfields[5]]) as usize + 1);
+            }
+            subfields = &subfields[(4 + length).min(subfields.len())..];
+        }
+        let size = size.ok_or_else(|| invalid("BGZF block without block size"))?;
+        let mut block = header.to_vec();
+        block.extend(&extra);
+        if size < block.len() {
+            return Err(invalid("BGZF block smaller than its header"));
+        }
+        block.resize(size, 0);
+        self.file.read_exact(&mut block[12 + extra.len()..])?;
+
+        self.block.clear();
+        GzDecoder::new(block.as_slice()).read_to_end(&mut self.block)?;
+        self.block_start = offset;
+        self.block_end = offset + size as u64;
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl Read for IndexedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let offset = (self.block_start << 16) | self.position as u64;
+            if offset >= self.end {
+                let Some((start, end)) = self.chunks.next() else {
+                    return Ok(0);
+                };
+                let loaded = start >> 16 == self.block_start && !self.block.is_empty();
+                if !loaded && !self.load_block(start >> 16)? {
+                    return Ok(0);
+                }
+                self.position = (start & 0xffff) as usize;
+                self.end = end;
+                continue;
+            }
+            let limit = if self.end >> 16 == self.block_start {
+                ((self.end & 0xffff) as usize).min(self.block.len())
+            } else {
+                self.block.len()
+            };
+            if self.position >= limit {
+                // The chunk ends with this block, or continues in the next one
+                if self.block_end << 16 >= self.end || !self.load_block(self.block_end)? {
+                    self.end = 0;
+                }
+                continue;
+            }
+            let length = (limit - self.position).min(buf.len());
+            buf[..length].copy_from_slice(&self.block[self.position..self.position + length]);
+            self.position += length;
+            return Ok(length);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::bgzf_block;
+
+    /// The tabix header fields of a methylome with the sequence in the first, the position in the second column and one header line
+    fn header(names: &[&str]) -> Vec<u8> {
+        let names: Vec<u8> = names
+            .iter()
+            .flat_map(|n| [n.as_bytes(), b"\0"].concat())
+            .collect();
+        let mut header = Vec::new();
+        for field in [0, 1, 2, 0, b'#' as i32, 1, names.len() as i32] {
+            header.extend(field.to_le_bytes());
+        }
+        header.extend(names);
+        header
+    }
+
+    /// One bin with its chunks
+    fn bin(bin: u32, chunks: &[Chunk]) -> Vec<u8> {
+        let mut bytes = bin.to_le_bytes().to_vec();
+        bytes.extend((chunks.len() as i32).to_le_bytes());
+        for (start, end) in chunks {
+            bytes.extend(start.to_le_bytes());
+            bytes.extend(end.to_le_bytes());
+        }
+        bytes
+    }
+
+    const BLOCKS: [&str; 3] = [
+        "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl\n1\t100\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n1\t150\t-\tCG\t0\t8\t0.9999\tU\t0.0025\n",
+        "1\t1000000\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n",
+        "2\t100\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n",
+    ];
+
+    /// A bgzip methylome of three blocks, with the virtual offsets of the start and end of each block
+    fn methylome(name: &str) -> (PathBuf, Vec<Chunk>) {
+        let path = std::env::temp_dir().join(name);
+        let mut content = Vec::new();
+        let mut chunks = Vec::new();
+        for (i, block) in BLOCKS.iter().enumerate() {
+            let offset = content.len() as u64;
+            // The header line of the first block is not part of any chunk
+            let skip = if i == 0 {
+                BLOCKS[0].find('\n').unwrap() + 1
+            } else {
+                0
+            };
+            chunks.push((
+                (offset << 16) | skip as u64,
+                (offset << 16) | block.len() as u64,
+            ));
+            content.extend(bgzf_block(block.as_bytes()));
+        }
+        // Empty end-of-file block
+        content.extend(bgzf_block(b""));
+        fs::write(&path, content).unwrap();
+        (path, chunks)
+    }
+
+    fn read_all(mut reader: MethylomeReader) -> String {
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        content
+    }
+
+    fn contigs() -> Contigs {
+        let mut contigs = Contigs::new();
+        contigs.intern("1");
+        contigs.intern("2");
+        contigs
+    }
+
+    #[test]
+    fn query_tabix_index() {
+        let (path, chunks) = methylome("extractor_query.txt.gz");
+        let mut index = b"TBI\x01".to_vec();
+        index.extend(2i32.to_le_bytes());
+        index.extend(header(&["1", "2"]));
+        // Sites on contig 1 in the first and 62nd bin of 16 kb, on contig 2 in the first one
+        index.extend(2i32.to_le_bytes());
+        index.extend(bin(4681, &chunks[0..1]));
+        index.extend(bin(4681 + 61, &chunks[1..2]));
+        index.extend(0i32.to_le_bytes());
+        index.extend(1i32.to_le_bytes());
+        index.extend(bin(4681, &chunks[2..3]));
+        index.extend(0i32.to_le_bytes());
+        let index_path = std::env::temp_dir().join("extractor_query.txt.gz.tbi");
+        fs::write(&index_path, bgzf_block(&index)).unwrap();
+
+        let index = Index::find(&path).unwrap();
+        assert_eq!(Index::from_file(&index).unwrap().names, vec!["1", "2"]);
+        assert_eq!(
+            Index::from_file(&index).unwrap().chunks(0, 99, 150),
+            chunks[0..1]
+        );
+
+        let regions = vec![vec![(50, 250)], vec![]];
+        let reader = open_indexed(&path, &regions, &contigs()).unwrap().unwrap();
+        assert_eq!(read_all(reader), BLOCKS[0].split_once('\n').unwrap().1);
+
+        // Regions spanning several blocks are read in the order of the file, each site once
+        let regions = vec![
+            vec![(50, 250), (60, 120), (999_000, 1_000_100)],
+            vec![(1, 200)],
+        ];
+        let reader = open_indexed(&path, &regions, &contigs()).unwrap().unwrap();
+        assert_eq!(
+            read_all(reader),
+            BLOCKS[0].split_once('\n').unwrap().1.to_owned() + BLOCKS[1] + BLOCKS[2]
+        );
+        fs::remove_file(index_path).unwrap();
+        assert!(open_indexed(&path, &regions, &contigs()).unwrap().is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn query_csi_index() {
+        let (path, chunks) = methylome("extractor_query_csi.txt.gz");
+        // A coarser binning than tabix, the smallest bins span 2^20 bases with 2 levels above them
+        let mut index = b"CSI\x01".to_vec();
+        index.extend(20u32.to_le_bytes());
+        index.extend(2u32.to_le_bytes());
+        let aux = header(&["1", "2"]);
+        index.extend((aux.len() as i32).to_le_bytes());
+        index.extend(aux);
+        index.extend(2i32.to_le_bytes());
+        index.extend(1i32.to_le_bytes());
+        let mut leaf = 9u32.to_le_bytes().to_vec();
+        leaf.extend(0u64.to_le_bytes());
+        leaf.extend(&bin(9, &chunks[0..2])[4..]);
+        index.extend(leaf);
+        index.extend(0i32.to_le_bytes());
+        let index_path = std::env::temp_dir().join("extractor_query_csi.txt.gz.csi");
+        fs::write(&index_path, bgzf_block(&index)).unwrap();
+
+        let regions = vec![vec![(50, 250)], vec![(1, 200)]];
+        let reader = open_indexed(&path, &regions, &contigs()).unwrap().unwrap();
+        // Both sites on contig 1 are in the same bin, contig 2 has no records in the index
+        assert_eq!(
+            read_all(reader),
+            BLOCKS[0].split_once('\n').unwrap().1.to_owned() + BLOCKS[1]
+        );
+        fs::remove_file(index_path).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
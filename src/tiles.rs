@@ -0,0 +1,184 @@
+use crate::*;
+
+/// Header of the table of genome-wide tiles, one row per methylome, context and tile
+pub const TILES_HEADER: &str =
+    "sample\tcontext\tcontig\tstart\tend\tsites\tcoverage\tmean_level\tweighted_level\tfraction_methylated\n";
+
+/// Fixed-size bins tiling every contig of the genome, independent of the annotation.
+/// The bins of each contig are laid out like the windows of an absolute region as long as the contig.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tiling {
+    /// Layout of the bins of every contig, indexed by contig id. Contigs without a known length have no bins.
+    pub contigs: Vec<RegionLayout>,
+}
+
+impl Tiling {
+    /// Read the contig lengths from a `.fai` index or a chrom.sizes file, both starting with the name and length of a contig on every line.
+    /// The contigs are added to the dictionary, so that sites on contigs without genes are found as well.
+    pub fn from_sizes_file(
+        path: &str,
+        size: i32,
+        step: i32,
+        contigs: &mut Contigs,
+    ) -> Result<Self> {
+        if size <= 0 || step < 0 {
+            return Err(Error::Layout(format!(
+                "tiles need a positive size and step, got size {size} and step {step}"
+            )));
+        }
+        let mut lengths = Vec::new();
+        for line in lines_from_file(path)? {
+            let line = line?;
+            let mut fields = line.split_whitespace();
+            let (Some(name), Some(length)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            if name.starts_with('#') {
+                continue;
+            }
+            let id = contigs.intern(name);
+            if lengths.len() <= id {
+                lengths.resize(id + 1, 0);
+            }
+            lengths[id] = length.parse::<i32>()?;
+        }
+
+        let contigs = lengths
+            .into_iter()
+            .map(|length| RegionLayout {
+                length,
+                size,
+                step: if step == 0 { size } else { step },
+                absolute: true,
+                origin: 0,
+            })
+            .collect();
+        Ok(Tiling { contigs })
+    }
+
+    /// Empty bins of every contig
+    pub fn tiles(&self, context: Context) -> Tiles {
+        Tiles {
+            context,
+            bins: self
+                .contigs
+                .iter()
+                .map(|layout| vec![Summary::default(); layout.count()])
+                .collect(),
+        }
+    }
+}
+
+/// Methylation levels of the bins of every contig, for one sequence context of one methylome
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tiles {
+    pub context: Context,
+    /// Summaries of the bins of every contig, indexed by contig id
+    pub bins: Vec<Vec<Summary>>,
+}
+
+impl Tiles {
+    /// Add a site to every bin containing it. Bins cover the bases after their start up to and including their end, like BED intervals.
+    ///
+    /// Unlike windows, see `MethylationSite::place_on_axis`, bins do not share their boundary bases: the tiles of a contig are counted like the intervals of `bedtools makewindows`, so that every site of non-overlapping tiles is counted once.
+    /// Bins are only summarised, writing the sites of every bin of a genome to files of their own is not worth the disk space.
+    pub fn add(&mut self, site: &MethylationSite, tiling: &Tiling) {
+        let (Some(bins), Some(layout)) = (
+            self.bins.get_mut(site.chromosome),
+            tiling.contigs.get(site.chromosome),
+        ) else {
+            return;
+        };
+        // 0-based position of the site, within bins of start <= position < start + size
+        let position = site.location - 1;
+        // The first bin that may contain the site, bins with a later start can not
+        let first = ((position - layout.size + 1).max(0) + layout.step - 1) / layout.step;
+        for (i, bin) in bins.iter_mut().enumerate().skip(first as usize) {
+            let start = layout.start(i);
+            if start > position {
+                break;
+            }
+            bin.add(site);
+        }
+    }
+
+    pub fn merge(&mut self, other: Tiles) {
+        for (bins, other) in self.bins.iter_mut().zip(other.bins) {
+            for (bin, other) in bins.iter_mut().zip(&other) {
+                bin.merge(other);
+            }
+        }
+    }
+
+    /// Rows of the tile table, see `TILES_HEADER`
+    pub fn rows(&self, sample: &str, tiling: &Tiling, contigs: &Contigs) -> String {
+        let mut output = String::new();
+        for (id, bins) in self.bins.iter().enumerate() {
+            let layout = &tiling.contigs[id];
+            for (i, summary) in bins.iter().enumerate() {
+                let start = layout.start(i);
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\n",
+                    sample,
+                    self.context,
+                    contigs.name(id),
+                    start,
+                    (start + layout.size).min(layout.length),
+                    summary
+                ));
+            }
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(location: i32) -> MethylationSite {
+        MethylationSite {
+            chromosome: 0,
+            location,
+            strand: Strand::Sense,
+            context: Context::CG,
+            methylated: 1,
+            total: 2,
+            posterior: None,
+            status: None,
+            level: 0.5,
+            original: String::new(),
+        }
+    }
+
+    #[test]
+    fn overlapping_tiles() {
+        let layout = RegionLayout {
+            length: 25_000,
+            size: 10_000,
+            step: 5_000,
+            absolute: true,
+            origin: 0,
+        };
+        let tiling = Tiling {
+            contigs: vec![layout],
+        };
+        let mut tiles = tiling.tiles(Context::CG);
+        assert_eq!(tiles.bins[0].len(), 5);
+
+        // Base 10000 is the last base of the first tile, base 10001 the first base after it
+        tiles.add(&site(10_000), &tiling);
+        tiles.add(&site(10_001), &tiling);
+        tiles.add(&site(24_999), &tiling);
+        let sites: Vec<usize> = tiles.bins[0].iter().map(|s| s.sites).collect();
+        assert_eq!(sites, vec![1, 2, 1, 1, 1]);
+
+        let rows = tiles.rows("G0.txt", &tiling, &{
+            let mut contigs = Contigs::new();
+            contigs.intern("Chr1");
+            contigs
+        });
+        assert!(rows.starts_with("G0.txt\tCG\tChr1\t0\t10000\t1\t2\t"));
+        assert!(rows.contains("G0.txt\tCG\tChr1\t20000\t25000\t1\t2\t"));
+    }
+}
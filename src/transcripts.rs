@@ -0,0 +1,384 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+use itertools::Itertools;
+
+use crate::*;
+
+/// Header of the table of methylation levels per feature class of the transcript models, one row per methylome, context and class
+pub const FEATURES_HEADER: &str =
+    "sample\tcontext\tclass\tsites\tcoverage\tmean_level\tweighted_level\tfraction_methylated\n";
+
+/// Maximum depth of the Parent chain of GFF3 features that is followed to find the gene of an exon
+const MAX_PARENT_DEPTH: usize = 8;
+
+/// Class of a gene body site within the transcript model of its gene
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeatureClass {
+    FivePrimeUtr,
+    Cds,
+    ThreePrimeUtr,
+    /// Exon of a transcript without coding sequence
+    Exon,
+    Intron,
+}
+
+impl FeatureClass {
+    pub const ALL: [FeatureClass; 5] = [
+        FeatureClass::FivePrimeUtr,
+        FeatureClass::Cds,
+        FeatureClass::ThreePrimeUtr,
+        FeatureClass::Exon,
+        FeatureClass::Intron,
+    ];
+}
+
+impl Display for FeatureClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FeatureClass::FivePrimeUtr => write!(f, "five_prime_UTR"),
+            FeatureClass::Cds => write!(f, "CDS"),
+            FeatureClass::ThreePrimeUtr => write!(f, "three_prime_UTR"),
+            FeatureClass::Exon => write!(f, "exon"),
+            FeatureClass::Intron => write!(f, "intron"),
+        }
+    }
+}
+
+/// Exon-intron structure of a gene. The exons of all its transcripts are merged, the coding sequence spans from the first to the last CDS base of any transcript.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Transcript {
+    /// First and last base of each exon, 1-based and inclusive like the positions of the sites, sorted and non-overlapping after `normalize`
+    pub exons: Vec<(i32, i32)>,
+    /// First and last base of the coding sequence, 1-based and inclusive, `None` for non-coding transcripts
+    pub cds: Option<(i32, i32)>,
+}
+
+impl Transcript {
+    /// Transcript of a BED12 line: the blocks are the exons, thickStart to thickEnd the coding sequence.
+    /// Block starts are relative to the start of the gene. The 0-based, half-open blocks are converted to their first and last base, like the exons of GFF3 and GTF files.
+    pub fn from_bed_blocks(
+        start: i32,
+        thick_start: &str,
+        thick_end: &str,
+        sizes: &str,
+        starts: &str,
+    ) -> Option<Self> {
+        let list = |s: &str| -> Option<Vec<i32>> {
+            s.split(',')
+                .filter(|n| !n.is_empty())
+                .map(|n| n.parse().ok())
+                .collect()
+        };
+        let exons: Vec<(i32, i32)> = list(starts)?
+            .into_iter()
+            .zip(list(sizes)?)
+            .map(|(offset, size)| (start + offset + 1, start + offset + size))
+            .collect();
+        let (thick_start, thick_end): (i32, i32) =
+            (thick_start.parse().ok()?, thick_end.parse().ok()?);
+        let mut transcript = Transcript {
+            exons,
+            cds: (thick_start < thick_end).then_some((thick_start + 1, thick_end)),
+        };
+        transcript.normalize();
+        Some(transcript)
+    }
+
+    /// Sort the exons and merge overlapping or adjacent exons, e.g. of alternative transcripts
+    pub fn normalize(&mut self) {
+        self.exons.sort_unstable();
+        self.exons = self
+            .exons
+            .iter()
+            .copied()
+            .coalesce(|a, b| {
+                if b.0 <= a.1 + 1 {
+                    Ok((a.0, a.1.max(b.1)))
+                } else {
+                    Err((a, b))
+                }
+            })
+            .collect();
+    }
+
+    fn exon(&self, location: i32) -> Option<usize> {
+        self.exons
+            .iter()
+            .position(|(start, end)| *start <= location && location <= *end)
+    }
+
+    /// Feature class of a location. Untranslated regions are upstream or downstream of the coding sequence in the direction of transcription.
+    pub fn classify(&self, location: i32, strand: &Strand) -> FeatureClass {
+        if self.exon(location).is_none() {
+            return FeatureClass::Intron;
+        }
+        match self.cds {
+            None => FeatureClass::Exon,
+            Some((start, end)) if start <= location && location <= end => FeatureClass::Cds,
+            Some((start, _)) => match (location < start, strand) {
+                (true, Strand::Sense) | (false, Strand::Antisense) => FeatureClass::FivePrimeUtr,
+                _ => FeatureClass::ThreePrimeUtr,
+            },
+        }
+    }
+
+    /// Length of the spliced transcript, the summed length of all exons
+    pub fn spliced_length(&self) -> i32 {
+        self.exons.iter().map(|(start, end)| end - start + 1).sum()
+    }
+
+    /// Position of a location on the spliced transcript, counted from 0 at its 5' end up to the spliced length minus one. `None` for intronic locations.
+    pub fn spliced_offset(&self, location: i32, strand: &Strand) -> Option<i32> {
+        let exon = self.exon(location)?;
+        let offset = self.exons[..exon]
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .sum::<i32>()
+            + location
+            - self.exons[exon].0;
+        match strand {
+            Strand::Sense => Some(offset),
+            Strand::Antisense => Some(self.spliced_length() - 1 - offset),
+        }
+    }
+}
+
+/// Exon and CDS features of a GFF3/GTF annotation, collected while reading the annotation and attached to their genes afterwards
+#[derive(Default)]
+pub struct TranscriptParts {
+    /// Exons and coding sequences with the ids of the features they belong to
+    parts: Vec<(String, bool, i32, i32)>,
+    /// Parent of each GFF3 feature, to find the gene of exons of transcripts
+    parents: HashMap<String, String>,
+    /// Name of each GFF3 feature, if genes are named by another attribute than their ID
+    names: HashMap<String, String>,
+}
+
+impl TranscriptParts {
+    /// Collect the exon or CDS feature of an annotation line, and the parent of every other feature
+    pub fn read_line(&mut self, s: &str, format: AnnotationFormat, name_attribute: Option<&str>) {
+        let Some((_, _, feature_type, start, end, _, _, _, attributes)) =
+            s.split('\t').collect_tuple()
+        else {
+            return;
+        };
+        let attributes: Vec<(&str, &str)> = format.attributes(attributes).collect();
+        let find = |key: &str| attributes.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+
+        let owners: Vec<&str> = match format {
+            AnnotationFormat::Gtf => ["transcript_id", "gene_id"]
+                .iter()
+                .filter_map(|key| find(key))
+                .collect(),
+            _ => find("Parent").map_or_else(Vec::new, |p| p.split(',').collect()),
+        };
+        if let (Some(id), AnnotationFormat::Gff) = (find("ID"), format) {
+            if let Some(parent) = owners.first() {
+                self.parents.insert(id.to_owned(), parent.to_string());
+            }
+            if let Some(name) = name_attribute.and_then(find) {
+                self.names.insert(id.to_owned(), name.to_owned());
+            }
+        }
+
+        let is_cds = match feature_type {
+            "exon" => false,
+            "CDS" => true,
+            _ => return,
+        };
+        let (Ok(start), Ok(end)) = (start.parse::<i32>(), end.parse::<i32>()) else {
+            return;
+        };
+        for owner in owners {
+            self.parts.push((owner.to_owned(), is_cds, start, end));
+        }
+    }
+
+    /// Attach the collected exons and coding sequences to the genes they belong to, following the Parent chain of GFF3 features up to the gene.
+    /// Genes without exons keep no transcript model.
+    pub fn attach(self, genes: &mut [Gene]) {
+        let mut by_name: HashMap<String, Vec<usize>> = HashMap::new();
+        for (i, gene) in genes.iter().enumerate() {
+            by_name.entry(gene.name.clone()).or_default().push(i);
+        }
+        let gene_of = |id: &str| {
+            let mut id = id;
+            for _ in 0..MAX_PARENT_DEPTH {
+                let name = self.names.get(id).map_or(id, String::as_str);
+                if let Some(genes) = by_name.get(name) {
+                    return Some(genes);
+                }
+                id = self.parents.get(id)?;
+            }
+            None
+        };
+
+        // GTF exons name both their transcript and their gene, only the first match is used
+        let mut attached = HashSet::new();
+        for (owner, is_cds, start, end) in &self.parts {
+            let Some(indices) = gene_of(owner) else {
+                continue;
+            };
+            for &i in indices {
+                if !attached.insert((i, *is_cds, *start, *end)) {
+                    continue;
+                }
+                let transcript = genes[i].transcript.get_or_insert_with(Transcript::default);
+                if *is_cds {
+                    transcript.cds = Some(match transcript.cds {
+                        Some((s, e)) => (s.min(*start), e.max(*end)),
+                        None => (*start, *end),
+                    });
+                } else {
+                    transcript.exons.push((*start, *end));
+                }
+            }
+        }
+        for gene in genes.iter_mut() {
+            if let Some(transcript) = gene.transcript.as_mut() {
+                // Coding sequences without exons, e.g. of annotations listing only CDS features, are exons as well
+                if transcript.exons.is_empty() {
+                    transcript.exons.extend(transcript.cds);
+                }
+                transcript.normalize();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GFF: [&str; 6] = [
+        "1\tAraport11\tgene\t1000\t2000\t.\t-\t.\tID=G1;Name=NAC001",
+        "1\tAraport11\tmRNA\t1000\t2000\t.\t-\t.\tID=G1.1;Parent=G1",
+        "1\tAraport11\texon\t1000\t1200\t.\t-\t.\tParent=G1.1",
+        "1\tAraport11\texon\t1500\t2000\t.\t-\t.\tID=G1.1:exon2;Parent=G1.1",
+        "1\tAraport11\tCDS\t1100\t1200\t.\t-\t.\tParent=G1.1",
+        "1\tAraport11\tCDS\t1500\t1800\t.\t-\t.\tParent=G1.1",
+    ];
+
+    fn transcript() -> Transcript {
+        Transcript {
+            exons: vec![(1000, 1200), (1500, 2000)],
+            cds: Some((1100, 1800)),
+        }
+    }
+
+    #[test]
+    fn classify_sites() {
+        let transcript = transcript();
+        assert_eq!(
+            transcript.classify(1300, &Strand::Antisense),
+            FeatureClass::Intron
+        );
+        assert_eq!(
+            transcript.classify(1150, &Strand::Antisense),
+            FeatureClass::Cds
+        );
+        // Transcribed from the end on the - strand
+        assert_eq!(
+            transcript.classify(1900, &Strand::Antisense),
+            FeatureClass::FivePrimeUtr
+        );
+        assert_eq!(
+            transcript.classify(1050, &Strand::Antisense),
+            FeatureClass::ThreePrimeUtr
+        );
+        assert_eq!(
+            transcript.classify(1050, &Strand::Sense),
+            FeatureClass::FivePrimeUtr
+        );
+        let non_coding = Transcript {
+            cds: None,
+            ..transcript
+        };
+        assert_eq!(
+            non_coding.classify(1050, &Strand::Sense),
+            FeatureClass::Exon
+        );
+    }
+
+    #[test]
+    fn spliced_offsets() {
+        let transcript = transcript();
+        assert_eq!(transcript.spliced_length(), 702);
+        assert_eq!(transcript.spliced_offset(1100, &Strand::Sense), Some(100));
+        assert_eq!(transcript.spliced_offset(1600, &Strand::Sense), Some(301));
+        assert_eq!(transcript.spliced_offset(1300, &Strand::Sense), None);
+        assert_eq!(transcript.spliced_offset(2000, &Strand::Antisense), Some(0));
+        assert_eq!(
+            transcript.spliced_offset(1100, &Strand::Antisense),
+            Some(601)
+        );
+    }
+
+    #[test]
+    fn spliced_offsets_at_exon_boundaries() {
+        let transcript = transcript();
+        // The last base of an exon and the first base of the next one are neighbours on the spliced transcript
+        assert_eq!(transcript.spliced_offset(1200, &Strand::Sense), Some(200));
+        assert_eq!(transcript.spliced_offset(1500, &Strand::Sense), Some(201));
+        assert_eq!(transcript.spliced_offset(1201, &Strand::Sense), None);
+        assert_eq!(transcript.spliced_offset(1499, &Strand::Sense), None);
+        // Both strands cover the offsets from 0 to the spliced length minus one
+        assert_eq!(transcript.spliced_offset(1000, &Strand::Sense), Some(0));
+        assert_eq!(transcript.spliced_offset(2000, &Strand::Sense), Some(701));
+        assert_eq!(
+            transcript.spliced_offset(1000, &Strand::Antisense),
+            Some(701)
+        );
+        assert_eq!(
+            transcript.spliced_offset(1200, &Strand::Antisense),
+            Some(501)
+        );
+        assert_eq!(
+            transcript.spliced_offset(1500, &Strand::Antisense),
+            Some(500)
+        );
+    }
+
+    #[test]
+    fn transcript_from_bed_blocks() {
+        // The BED12 blocks of the GFF exons, 0-based and half-open
+        let transcript =
+            Transcript::from_bed_blocks(999, "1099", "1800", "201,501,", "0,500,").unwrap();
+        assert_eq!(transcript, self::transcript());
+        let non_coding = Transcript::from_bed_blocks(999, "1000", "1000", "201", "0").unwrap();
+        assert_eq!(non_coding.cds, None);
+    }
+
+    #[test]
+    fn attach_gff_exons() {
+        let mut contigs = Contigs::new();
+        let mut parts = TranscriptParts::default();
+        let mut genes = Vec::new();
+        for line in GFF {
+            parts.read_line(line, AnnotationFormat::Gff, None);
+            genes.extend(Gene::from_gff_file_line(
+                line,
+                &mut contigs,
+                AnnotationFormat::Gff,
+                &[String::from("gene")],
+                None,
+                false,
+            ));
+        }
+        parts.attach(&mut genes);
+        assert_eq!(genes[0].transcript, Some(transcript()));
+
+        // Genes named by another attribute than their ID
+        let mut parts = TranscriptParts::default();
+        for line in GFF {
+            parts.read_line(line, AnnotationFormat::Gff, Some("Name"));
+        }
+        genes[0].name = String::from("NAC001");
+        genes[0].transcript = None;
+        parts.attach(&mut genes);
+        assert_eq!(genes[0].transcript, Some(transcript()));
+    }
+}
@@ -1,44 +1,374 @@
-use std::{
-    fmt::Display,
-    fs::{File, OpenOptions},
-    io::{self, BufRead, Write},
-};
+use std::{fmt::Display, fs::OpenOptions, io::Write};
 
-use itertools::Itertools;
+use clap::ValueEnum;
 
 use crate::*;
 
-pub type Window = Vec<MethylationSite>;
-#[derive(Debug, Eq, PartialEq)]
+/// Header line of the window files, the methimpute layout
+const WINDOW_FILE_HEADER: &str = "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl\n";
+
+/// Sites placed in a window. The sites are summarised as they are placed and only buffered until they are written to the window file, so that memory does not grow with the size of the methylome or the overlap of the windows.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Window {
+    pub summary: Summary,
+    /// Sites that are not written to the window file yet
+    buffer: Vec<MethylationSite>,
+    /// File the sites are written to, `None` if they are only summarised and buffered
+    path: Option<String>,
+    /// Whether the file starts with a header line. Files of later chunks of a methylome have none, they are appended to the file of the first chunk.
+    header: bool,
+}
+
+impl Window {
+    pub fn push(&mut self, site: &MethylationSite) {
+        self.summary.add(site);
+        self.buffer.push(site.clone());
+    }
+
+    /// Number of sites placed in the window, including those already written
+    pub fn len(&self) -> usize {
+        self.summary.sites
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a site is among the sites that are not written yet. Sites already written to the window file are not found.
+    #[cfg(test)]
+    pub fn is_buffered(&self, site: &MethylationSite) -> bool {
+        self.buffer.contains(site)
+    }
+
+    /// Append the buffered sites to the window file, creating it with a header line first
+    fn flush(&mut self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        // Files of later chunks are only created for sites
+        if !self.header && self.buffer.is_empty() {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        if self.header && file.metadata()?.len() == 0 {
+            file.write_all(WINDOW_FILE_HEADER.as_bytes())?;
+        }
+        let mut lines = String::new();
+        for site in self.buffer.drain(..) {
+            lines += &site.original;
+            lines.push('\n');
+        }
+        file.write_all(lines.as_bytes())?;
+        Ok(())
+    }
+
+    /// Add the sites of the same window of a later chunk, appending its file to the file of this window
+    fn merge(&mut self, mut other: Window) -> Result<()> {
+        self.summary.merge(&other.summary);
+        if let (Some(path), Some(part)) = (self.path.clone(), &other.path) {
+            if Path::new(part).exists() {
+                self.flush()?;
+                let mut file = OpenOptions::new().append(true).open(path)?;
+                io::copy(&mut File::open(part)?, &mut file)?;
+                fs::remove_file(part)?;
+            }
+        }
+        self.buffer.append(&mut other.buffer);
+        Ok(())
+    }
+}
+
+/// Windows of each selected sequence context, in the order the contexts were selected
+pub type WindowsByContext = Vec<(Context, Windows)>;
+
+/// Whether the windows of a region are measured in base pairs or in percent of the region length
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WindowMode {
+    Absolute,
+    Relative,
+}
+
+/// Reference point of anchored profiles
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Anchor {
+    /// Transcription start site, the start of genes on the + strand and the end of genes on the - strand
+    Tss,
+    /// Transcription end site, the end of genes on the + strand and the start of genes on the - strand
+    Tes,
+}
+
+impl Display for Anchor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anchor::Tss => write!(f, "tss"),
+            Anchor::Tes => write!(f, "tes"),
+        }
+    }
+}
+
+/// Windows of one region: the length of the region in bp and the size of and step between the windows, in bp if absolute or in percent otherwise
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegionLayout {
+    pub length: i32,
+    pub size: i32,
+    pub step: i32,
+    pub absolute: bool,
+    /// Position of the first window on the window axis, negative for anchored profiles starting upstream of the anchor
+    pub origin: i32,
+}
+
+impl RegionLayout {
+    /// Extent of the window axis, the length of the region in bp or 100%
+    pub fn extent(&self) -> i32 {
+        if self.absolute {
+            self.length
+        } else {
+            100
+        }
+    }
+
+    /// Number of windows, enough to cover the whole extent
+    pub fn count(&self) -> usize {
+        ((self.extent() + self.step - 1) / self.step).max(0) as usize
+    }
+
+    /// Start of a window on the window axis, also the name of its directory
+    pub fn start(&self, window: usize) -> i32 {
+        self.origin + window as i32 * self.step
+    }
+
+    pub fn unit(&self) -> &'static str {
+        if self.absolute {
+            "bp"
+        } else {
+            "%"
+        }
+    }
+}
+
+/// Window layout of the upstream, gene and downstream region, see `Args::layout`.
+///
+/// Anchored profiles have a single axis of signed distances to the anchor, stored as the gene region. The upstream and downstream lengths then give the range of the axis.
+/// Spliced windows over the exons of the transcript models use the layout of the gene region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub upstream: RegionLayout,
+    pub gene: RegionLayout,
+    pub downstream: RegionLayout,
+    pub anchor: Option<Anchor>,
+    /// Whether exonic sites are also placed in windows over the spliced transcript
+    pub spliced: bool,
+}
+
+impl Layout {
+    /// Regions that are divided into windows, only the single axis of anchored profiles
+    pub fn regions(&self) -> Vec<Region> {
+        match self.anchor {
+            Some(_) => vec![Region::Gene],
+            None if self.spliced => vec![
+                Region::Upstream,
+                Region::Gene,
+                Region::Downstream,
+                Region::Spliced,
+            ],
+            None => vec![Region::Upstream, Region::Gene, Region::Downstream],
+        }
+    }
+
+    /// Regions on the continuous axis from the upstream to the downstream region, without the spliced windows
+    pub fn axis(&self) -> Vec<Region> {
+        self.regions()
+            .into_iter()
+            .filter(|r| *r != Region::Spliced)
+            .collect()
+    }
+
+    /// Name of a region in the output directory and tables, the anchor for anchored profiles
+    pub fn name(&self, region: Region) -> String {
+        match self.anchor {
+            Some(anchor) if region == Region::Gene => anchor.to_string(),
+            _ => region.to_string(),
+        }
+    }
+
+    /// Number of windows of a region, none for the flanks of anchored profiles
+    pub fn count(&self, region: Region) -> usize {
+        if self.regions().contains(&region) {
+            self.get(region).count()
+        } else {
+            0
+        }
+    }
+
+    pub fn get(&self, region: Region) -> &RegionLayout {
+        match region {
+            Region::Upstream => &self.upstream,
+            Region::Gene | Region::Spliced => &self.gene,
+            Region::Downstream => &self.downstream,
+        }
+    }
+
+    /// Index of a window on the continuous axis from the first upstream to the last downstream window, `None` for spliced windows
+    pub fn axis_index(&self, region: Region, window: usize) -> Option<usize> {
+        let axis = self.axis();
+        let position = axis.iter().position(|r| *r == region)?;
+        Some(
+            axis[..position]
+                .iter()
+                .map(|r| self.count(*r))
+                .sum::<usize>()
+                + window,
+        )
+    }
+
+    /// Position of a window relative to the gene: negative distances to the start for upstream windows, distances from the start for gene windows and positive distances to the end for downstream windows, in bp or percent of each region
+    pub fn label(&self, region: Region, window: usize) -> String {
+        let layout = self.get(region);
+        let start = layout.start(window);
+        if self.anchor.is_some() {
+            return format!("{}{}", start, layout.unit());
+        }
+        match region {
+            Region::Upstream => format!("-{}{}", layout.extent() - start, layout.unit()),
+            Region::Gene | Region::Spliced => format!("{}{}", start, layout.unit()),
+            Region::Downstream => format!("+{}{}", start, layout.unit()),
+        }
+    }
+
+    /// The layout with upstream and downstream swapped
+    pub fn inverted(mut self) -> Self {
+        std::mem::swap(&mut self.upstream, &mut self.downstream);
+        self
+    }
+
+    /// Check that all windows have a positive size and step
+    pub fn check(&self) -> Result<()> {
+        for region in [Region::Upstream, Region::Gene, Region::Downstream] {
+            let layout = self.get(region);
+            if layout.size <= 0 || layout.step <= 0 || layout.length < 0 {
+                return Err(Error::Layout(format!(
+                    "{region} windows need a positive size and step and a non-negative length, got size {}, step {} and length {}",
+                    layout.size, layout.step, layout.length
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct Windows {
     pub upstream: Vec<Window>,
     pub gene: Vec<Window>,
     pub downstream: Vec<Window>,
+    pub spliced: Vec<Window>,
+    pub layout: Layout,
+    /// Sites of the gene bodies by their class in the transcript model, in the order of `FeatureClass::ALL`
+    pub features: [Summary; 5],
+    /// Sites of every gene and window, if requested with --matrix
+    pub matrix: Option<GeneMatrix>,
+    /// Sites of the flanks and body of every gene, if requested with --gene-summary
+    pub gene_summaries: Option<GeneSummaries>,
+    /// Number of sites buffered in all windows
+    buffered: usize,
+    /// Number of buffered sites at which they are written to the window files, 0 to buffer all sites
+    buffer_size: usize,
 }
 
 impl Windows {
     pub fn new(max_gene_length: i32, args: &Args) -> Self {
-        let gene_window_count = if args.absolute {
-            max_gene_length / args.window_step
-        } else {
-            100
-        };
-        let up_down_window_count = if args.absolute {
-            args.cutoff / args.window_step
-        } else {
-            100
-        };
+        let mut layout = args.layout(max_gene_length);
+        // Sites of inverted methylomes are placed with swapped flanks, which `inverse` swaps back
+        if args.invert {
+            layout = layout.inverted();
+        }
         Windows {
-            upstream: vec![Vec::new(); up_down_window_count as usize],
-            gene: vec![Vec::new(); gene_window_count as usize],
-            downstream: vec![Vec::new(); up_down_window_count as usize],
+            upstream: vec![Window::default(); layout.count(Region::Upstream)],
+            gene: vec![Window::default(); layout.count(Region::Gene)],
+            downstream: vec![Window::default(); layout.count(Region::Downstream)],
+            spliced: vec![Window::default(); layout.count(Region::Spliced)],
+            layout,
+            features: Default::default(),
+            matrix: args.matrix.then(GeneMatrix::default),
+            gene_summaries: args.gene_summary.then(GeneSummaries::default),
+            buffered: 0,
+            buffer_size: args.buffer_size,
+        }
+    }
+
+    /// Write the sites of every window to `{output_dir}/{region}/{window start}/{filename}` as they are placed, instead of only buffering them.
+    /// Later chunks of a methylome write to a `.part{chunk}` file next to it, which is appended by `merge`.
+    pub fn with_output(mut self, output_dir: &str, filename: &OsString, chunk: usize) -> Self {
+        let filename = match chunk {
+            0 => output_name(filename),
+            _ => format!("{}.part{}", output_name(filename), chunk),
+        };
+        for region in self.layout.regions() {
+            let layout = *self.layout.get(region);
+            let name = self.layout.name(region);
+            for (i, window) in self.get_mut(&region).iter_mut().enumerate() {
+                window.path = Some(format!(
+                    "{}/{}/{}/{}",
+                    output_dir,
+                    name,
+                    layout.start(i),
+                    filename
+                ));
+                window.header = chunk == 0;
+            }
+        }
+        self
+    }
+
+    /// Place a site in a window, writing all buffered sites to the window files once the buffer is full
+    pub fn push(&mut self, region: Region, window: usize, site: &MethylationSite) -> Result<()> {
+        self.get_mut(&region)[window].push(site);
+        self.buffered += 1;
+        if self.buffer_size > 0 && self.buffered >= self.buffer_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Add the sites of the windows of a later chunk of the same methylome. The windows of both chunks need to be saved.
+    pub fn merge(&mut self, mut other: Windows) -> Result<()> {
+        for region in self.layout.regions() {
+            let others = std::mem::take(other.get_mut(&region));
+            for (window, other) in self.get_mut(&region).iter_mut().zip(others) {
+                window.merge(other)?;
+            }
+        }
+        for (features, other) in self.features.iter_mut().zip(&other.features) {
+            features.merge(other);
+        }
+        if let (Some(matrix), Some(other)) = (self.matrix.as_mut(), other.matrix) {
+            matrix.merge(other);
+        }
+        if let (Some(summaries), Some(other)) = (self.gene_summaries.as_mut(), other.gene_summaries)
+        {
+            summaries.merge(other);
+        }
+        Ok(())
+    }
+
+    /// Write the buffered sites of all windows to their files
+    fn flush(&mut self) -> Result<()> {
+        for region in self.layout.regions() {
+            for window in self.get_mut(&region) {
+                if !window.buffer.is_empty() {
+                    window.flush()?;
+                }
+            }
         }
+        self.buffered = 0;
+        Ok(())
     }
     pub fn get(&self, region: Region) -> &Vec<Window> {
         match region {
             Region::Upstream => &self.upstream,
             Region::Gene => &self.gene,
             Region::Downstream => &self.downstream,
+            Region::Spliced => &self.spliced,
         }
     }
     pub fn get_mut<'a>(&'a mut self, location: &Region) -> &'a mut Vec<Window> {
@@ -46,114 +376,312 @@ impl Windows {
             Region::Upstream => &mut self.upstream,
             Region::Gene => &mut self.gene,
             Region::Downstream => &mut self.downstream,
+            Region::Spliced => &mut self.spliced,
         }
     }
+    /// Swap upstream and downstream and reverse the order of all windows, to switch from 5' to 3'
     pub fn inverse(mut self) -> Self {
-        self.upstream = self.downstream.iter().rev().map(|a| a.to_owned()).collect();
-        self.gene = self.gene.iter().rev().map(|a| a.to_owned()).collect();
-        self.downstream = self.upstream.iter().rev().map(|a| a.to_owned()).collect();
+        std::mem::swap(&mut self.upstream, &mut self.downstream);
+        self.upstream.reverse();
+        self.gene.reverse();
+        self.downstream.reverse();
+        self.spliced.reverse();
+        // The untranslated regions were classified on the inverted strand
+        self.features.swap(0, 2);
+        let count = self
+            .layout
+            .axis()
+            .iter()
+            .map(|r| self.layout.count(*r))
+            .sum();
+        self.matrix = self.matrix.map(|m| m.reversed(count));
+        self.gene_summaries = self.gene_summaries.map(GeneSummaries::inverse);
+        self.layout = self.layout.inverted();
         self
     }
 
+    /// Add a site assigned to a gene to the gene-level summaries and, with the windows it was placed in, to the gene-by-window matrix, if requested
+    pub fn add_to_gene(&mut self, gene: &Gene, placed: &[(Region, usize)], site: &MethylationSite) {
+        if let Some(summaries) = self.gene_summaries.as_mut() {
+            summaries.add(gene, site.region(gene), site);
+        }
+        let Some(matrix) = self.matrix.as_mut() else {
+            return;
+        };
+        for (region, window) in placed {
+            if let Some(index) = self.layout.axis_index(*region, *window) {
+                matrix.add(gene, index, site);
+            }
+        }
+    }
+
     pub fn distribution(&self) -> String {
         // In CSV format
         let mut output = String::new();
-        output += "Upstream\n";
-        for (i, window) in self.upstream.iter().enumerate() {
-            output.push_str(&format!("{},{}\n", i, window.len()));
-        }
-        output += "Gene\n";
-        for (i, window) in self.gene.iter().enumerate() {
-            output.push_str(&format!("{},{}\n", i, window.len()));
-        }
-        output += "Downstream\n";
-        for (i, window) in self.downstream.iter().enumerate() {
-            output.push_str(&format!("{},{}\n", i, window.len()));
+        for region in self.layout.regions() {
+            let name = self.layout.name(region);
+            let mut name = name.chars();
+            // Capitalized region name, e.g. Upstream or Tss
+            output += &name
+                .next()
+                .unwrap()
+                .to_uppercase()
+                .chain(name)
+                .collect::<String>();
+            output += "\n";
+            let layout = self.layout.get(region);
+            for (i, window) in self.get(region).iter().enumerate() {
+                output.push_str(&format!("{},{}\n", layout.start(i), window.len()));
+            }
         }
+        // One continuous axis over all regions, labelled with the position relative to the gene, e.g. -2048bp..0%..+2048bp in metagene mode
         output += "Combined\n";
-        for (i, window) in self
-            .upstream
-            .iter()
-            .chain(self.gene.iter())
-            .chain(self.downstream.iter())
-            .enumerate()
-        {
-            output.push_str(&format!("{},{}\n", i, window.len()));
+        let windows = self
+            .layout
+            .axis()
+            .into_iter()
+            .flat_map(|region| (0..self.get(region).len()).map(move |i| (region, i)));
+        for (index, (region, i)) in windows.enumerate() {
+            output.push_str(&format!(
+                "{},{},{}\n",
+                index,
+                self.layout.label(region, i),
+                self.get(region)[i].len()
+            ));
         }
 
         output
     }
 
-    pub fn save(
-        &self,
-        output_dir: &str,
-        filename: &OsString,
-        step: usize,
-        invert_direction: bool,
-    ) -> Result<()> {
-        for windows in vec![
-            (&self.upstream, "upstream"),
-            (&self.gene, "gene"),
-            (&self.downstream, "downstream"),
-        ]
-        .iter()
-        {
-            for (window, cg_sites) in windows.0.iter().enumerate() {
-                let output_file = format!(
-                    "{}/{}/{}/{}",
-                    output_dir,
-                    windows.1,
-                    window * step,
-                    filename.to_str().unwrap()
-                );
-                let mut file = OpenOptions::new()
-                    .append(true)
-                    .create(true)
-                    .open(&output_file)?;
-
-                let metadata = file.metadata();
-                if metadata.unwrap().len() == 0 {
-                    // On first write to file, create header line
-                    file.write_all("seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl\n".as_bytes())?;
-                }
-                file.write_all(cg_sites.iter().map(|e| &e.original).join("\n").as_bytes())?;
+    /// Rows of the summary table for the windows of one methylome, see `SUMMARY_HEADER`
+    pub fn summary(&self, sample: &str, context: Context) -> String {
+        let mut output = String::new();
+        for region in self.layout.regions() {
+            let layout = self.layout.get(region);
+            for (i, window) in self.get(region).iter().enumerate() {
+                let summary = &window.summary;
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\n",
+                    sample,
+                    context,
+                    self.layout.name(region),
+                    layout.start(i),
+                    summary
+                ));
+            }
+        }
+        output
+    }
+
+    /// Rows of the metagene profile table for the windows of one methylome, see `PROFILE_HEADER`.
+    ///
+    /// The window index runs continuously from the first upstream window to the last downstream window, spliced windows are only part of the summary table. The start label gives the position of the window relative to the gene, see `Layout::label`.
+    pub fn profile(&self, sample: &str, context: Context) -> String {
+        let mut output = String::new();
+        let mut index = 0;
+        for region in self.layout.axis() {
+            for (i, window) in self.get(region).iter().enumerate() {
+                let label = self.layout.label(region, i);
+                let summary = &window.summary;
+                output.push_str(&format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                    sample,
+                    context,
+                    self.layout.name(region),
+                    index,
+                    label,
+                    summary.sites,
+                    summary.coverage,
+                    Level(summary.weighted_level()),
+                    Level(summary.mean_level())
+                ));
+                index += 1;
             }
         }
+        output
+    }
+
+    /// Rows of the feature class table for the sites of one methylome, see `FEATURES_HEADER`
+    pub fn features(&self, sample: &str, context: Context) -> String {
+        FeatureClass::ALL
+            .iter()
+            .zip(&self.features)
+            .map(|(class, summary)| format!("{sample}\t{context}\t{class}\t{summary}\n"))
+            .collect()
+    }
+
+    /// Write the remaining buffered sites of all windows to their files. Every window file is created, even if no sites were placed in the window.
+    pub fn save(&mut self) -> Result<()> {
+        for region in self.layout.regions() {
+            for window in self.get_mut(&region) {
+                window.flush()?;
+            }
+        }
+        self.buffered = 0;
         Ok(())
     }
 }
 
+/// Counts of the sites of a methylome file or of one chunk of it, logged once per file after its chunks are merged
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SiteCounts {
+    pub sites: usize,
+    /// Sites within the region of at least one feature of each set
+    pub assigned: Vec<usize>,
+    /// Sites within the regions of several features of each set
+    pub ambiguous: Vec<usize>,
+    /// Whether the sites were found to be unsorted, see `Sweep`
+    pub unsorted: bool,
+}
+
+impl SiteCounts {
+    fn new(sets: usize) -> Self {
+        SiteCounts {
+            assigned: vec![0; sets],
+            ambiguous: vec![0; sets],
+            ..Default::default()
+        }
+    }
+
+    fn merge(&mut self, other: &SiteCounts) {
+        self.sites += other.sites;
+        for (a, b) in self.assigned.iter_mut().zip(&other.assigned) {
+            *a += b;
+        }
+        for (a, b) in self.ambiguous.iter_mut().zip(&other.ambiguous) {
+            *a += b;
+        }
+        self.unsorted |= other.unsorted;
+    }
+
+    pub fn log(&self, filename: &OsString, sets: &[FeatureSet], args: &Args) {
+        let filename = filename.to_string_lossy();
+        println!("Done with {} methylation sites of {}", self.sites, filename);
+        for (s, set) in sets.iter().enumerate() {
+            println!(
+                "{} of {} sites overlap more than one feature of {}, assigned with policy {:?}",
+                self.ambiguous[s], self.assigned[s], set.args.genome, args.overlap
+            );
+        }
+        if self.unsorted {
+            println!(
+                "{filename} is not sorted by contig and position, its sites were looked up in the feature index"
+            );
+        }
+    }
+}
+
+/// Windows of each context for every set, in the order of the sets, the tiles of each context and the counts of the sites of a methylome
+pub type Extraction = (Vec<WindowsByContext>, Vec<Tiles>, SiteCounts);
+
+/// Place the sites of a methylome file in the windows of every feature set and in the genome-wide tiles, in a single pass over the file.
+/// The sites are written to the window files named after the methylome file as they are placed, with at most `--buffer-size` sites buffered per set and context.
+/// The methylome can also be one chunk of a file, see `open_chunks` and `open_cache`. The index of the chunk names the files its sites are written to, see `Windows::with_output`.
+/// Returns the windows and tiles of the sites with their counts, which are logged by the caller once the chunks of a file are merged.
 pub fn extract_windows(
-    methylome_file: File,
-    genome: Vec<GenesByStrand>,
-    max_gene_length: i32,
-    args: Args,
-) -> Result<Windows> {
-    let mut last_gene: Option<&Gene> = None;
-
-    let mut windows = Windows::new(max_gene_length, &args);
-
-    let lines = io::BufReader::new(methylome_file).lines();
-    for (i, line_result) in lines.enumerate().skip(1) {
-        // skip header row
-        if let Ok(line) = line_result {
-            if i % 100_000 == 0 {
-                println!("Done with methylation site {i} ");
-            }
+    methylome: Methylome,
+    sets: &[FeatureSet],
+    tiling: Option<&Tiling>,
+    contigs: &Contigs,
+    filename: &OsString,
+    chunk: usize,
+    args: &Args,
+) -> Result<Extraction> {
+    let contexts = args.contexts()?;
+    let mut windows_by_set: Vec<WindowsByContext> = sets
+        .iter()
+        .map(|set| {
+            contexts
+                .iter()
+                .map(|context| {
+                    let output_dir = format!("{}/{}", set.args.output_dir, context);
+                    let windows = Windows::new(set.max_gene_length, &set.args);
+                    // The window files are named by the orientation of the windows after `inverse`, which is its own inverse
+                    let windows = if args.invert {
+                        windows
+                            .inverse()
+                            .with_output(&output_dir, filename, chunk)
+                            .inverse()
+                    } else {
+                        windows.with_output(&output_dir, filename, chunk)
+                    };
+                    (*context, windows)
+                })
+                .collect()
+        })
+        .collect();
+    let mut tiles: Vec<Tiles> = tiling
+        .map(|tiling| contexts.iter().map(|c| tiling.tiles(*c)).collect())
+        .unwrap_or_default();
 
-            // If cg site could not be extracted from a file line, continue with the next line. Happens on header rows, for example.
-            let Ok(cg) = MethylationSite::from_methylome_file_line(&line, args.invert) else {continue;};
+    let mut sweeps: Vec<Sweep> = sets
+        .iter()
+        .map(|set| {
+            Sweep::new(
+                &set.genome,
+                set.layout.upstream.length,
+                set.layout.downstream.length,
+            )
+        })
+        .collect();
 
-            if last_gene.is_none() || !cg.is_in_gene(last_gene.unwrap(), args.cutoff) {
-                last_gene = cg.find_gene(&genome, args.cutoff);
-            }
-            if let Some(gene) = last_gene {
-                cg.place_in_windows(gene, &mut windows, &args);
-                continue;
+    let mut counts = SiteCounts::new(sets.len());
+    for site in methylome.sites(filename, contigs, args) {
+        let (cg, stranded) = site?;
+        counts.sites += 1;
+        // Route the site to the windows of its context, skipping contexts that were not selected
+        let Some(context) = contexts.iter().position(|c| *c == cg.context) else {
+            continue;
+        };
+        if let Some(tiling) = tiling {
+            tiles[context].add(&cg, tiling);
+        }
+        let mirrored = (!stranded).then(|| cg.mirrored());
+        let sites = [Some(cg), mirrored];
+
+        for (s, sweep) in sweeps.iter_mut().enumerate() {
+            let windows = &mut windows_by_set[s][context].1;
+            for cg in sites.iter().flatten() {
+                let genes = sweep.find_genes(cg);
+                if genes.is_empty() {
+                    continue;
+                }
+                counts.assigned[s] += 1;
+                if genes.len() > 1 {
+                    counts.ambiguous[s] += 1;
+                }
+                for gene in args.overlap.select(cg, genes) {
+                    let placed = cg.place_in_windows(gene, windows)?;
+                    windows.add_to_gene(gene, &placed, cg);
+                }
             }
         }
     }
-    Ok(windows)
+    counts.unsorted = sweeps.iter().any(Sweep::is_unsorted);
+    for (_, windows) in windows_by_set.iter_mut().flatten() {
+        windows.save()?;
+    }
+    Ok((windows_by_set, tiles, counts))
+}
+
+/// Merge the windows, tiles and site counts of the chunks of a methylome file into those of the first chunk, in the order of the chunks.
+/// The window files list the sites in the order of the methylome file and the results do not depend on which thread processed which chunk.
+pub fn merge_chunks(chunks: Vec<Extraction>) -> Result<Extraction> {
+    let mut chunks = chunks.into_iter();
+    let Some((mut windows_by_set, mut tiles, mut counts)) = chunks.next() else {
+        return Ok(Default::default());
+    };
+    for (other_windows, other_tiles, other_counts) in chunks {
+        counts.merge(&other_counts);
+        let others = other_windows.into_iter().flatten();
+        for ((_, windows), (_, other)) in windows_by_set.iter_mut().flatten().zip(others) {
+            windows.merge(other)?;
+        }
+        for (tiles, other) in tiles.iter_mut().zip(other_tiles) {
+            tiles.merge(other);
+        }
+    }
+    Ok((windows_by_set, tiles, counts))
 }
 
 impl Display for Windows {
@@ -183,6 +711,7 @@ mod test {
             output_dir: "/home/constantin/windows".to_string(),
             absolute: true,
             cutoff: 2048,
+            ..Default::default()
         };
         let windows = super::Windows::new(4096, &args);
         assert_eq!(windows.upstream.len(), 8);
@@ -202,10 +731,80 @@ mod test {
             output_dir: "/home/constantin/windows".to_string(),
             absolute: false,
             cutoff: 2048,
+            ..Default::default()
         };
         let windows = super::Windows::new(4096, &args);
         assert_eq!(windows.upstream.len(), 100);
         assert_eq!(windows.gene.len(), 100);
         assert_eq!(windows.downstream.len(), 100);
     }
+
+    #[test]
+    fn new_relative_with_step() {
+        let args = Args {
+            window_size: 5,
+            cutoff: 2048,
+            ..Default::default()
+        };
+        let windows = super::Windows::new(4096, &args);
+        assert_eq!(windows.upstream.len(), 20);
+        assert_eq!(windows.gene.len(), 20);
+    }
+
+    #[test]
+    fn new_per_region() {
+        let args = Args {
+            window_size: 5,
+            cutoff: 2048,
+            upstream_length: Some(1000),
+            upstream_window_size: Some(100),
+            upstream_mode: Some(super::WindowMode::Absolute),
+            downstream_length: Some(3000),
+            downstream_window_size: Some(250),
+            downstream_mode: Some(super::WindowMode::Absolute),
+            ..Default::default()
+        };
+        let windows = super::Windows::new(4096, &args);
+        assert_eq!(windows.upstream.len(), 10);
+        assert_eq!(windows.gene.len(), 20);
+        assert_eq!(windows.downstream.len(), 12);
+        assert_eq!(windows.layout.downstream.start(11), 2750);
+
+        // The flanks are swapped back by inverse
+        let inverted = super::Windows::new(
+            4096,
+            &Args {
+                invert: true,
+                ..args
+            },
+        )
+        .inverse();
+        assert_eq!(inverted.upstream.len(), 10);
+        assert_eq!(inverted.layout, windows.layout);
+    }
+
+    #[test]
+    fn merge_site_counts_of_chunks() {
+        let counts = |sites, assigned, ambiguous, unsorted| {
+            (
+                Vec::new(),
+                Vec::new(),
+                super::SiteCounts {
+                    sites,
+                    assigned: vec![assigned],
+                    ambiguous: vec![ambiguous],
+                    unsorted,
+                },
+            )
+        };
+        let (_, _, merged) = super::merge_chunks(vec![
+            counts(100_000, 40, 2, false),
+            counts(50_000, 10, 1, true),
+        ])
+        .unwrap();
+        assert_eq!(merged.sites, 150_000);
+        assert_eq!(merged.assigned, vec![50]);
+        assert_eq!(merged.ambiguous, vec![3]);
+        assert!(merged.unsorted);
+    }
 }
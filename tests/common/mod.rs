@@ -0,0 +1,21 @@
+//! Fixtures shared by the integration tests and the unit tests of the crate, which include this file with `#[path]`
+
+use std::io::Write;
+
+use flate2::{write::DeflateEncoder, Compression, Crc};
+
+/// A BGZF block as written by bgzip: a gzip member with the size of the block in the `BC` extra subfield
+pub fn bgzf_block(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let size = (18 + compressed.len() + 8 - 1) as u16;
+    let mut block = vec![31, 139, 8, 4, 0, 0, 0, 0, 0, 255, 6, 0, b'B', b'C', 2, 0];
+    block.extend(size.to_le_bytes());
+    block.extend(compressed);
+    let mut crc = Crc::new();
+    crc.update(data);
+    block.extend(crc.sum().to_le_bytes());
+    block.extend((data.len() as u32).to_le_bytes());
+    block
+}
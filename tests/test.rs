@@ -1,5 +1,5 @@
-use extractor::arguments::Args;
-use extractor::extract;
+use extractor::arguments::{Anchor, Args, IndexArgs, OverlapPolicy, Truncation, WindowMode};
+use extractor::{extract, index};
 use serial_test::serial;
 
 // #[test]
@@ -14,6 +14,7 @@ use serial_test::serial;
 //         output_dir: "/home/constantin/windows".to_string(),
 //         absolute: false,
 //         cutoff: 2048,
+//         ..Default::default()
 //     };
 //     match extract(args) {
 //         Ok(_) => println!("Done!"),
@@ -32,9 +33,778 @@ use serial_test::serial;
 //         output_dir: "/home/constantin/windows".to_string(),
 //         absolute: true,
 //         cutoff: 2048,
+//         ..Default::default()
 //     };
 //     match extract(args) {
 //         Ok(_) => println!("Done!"),
 //         Err(e) => panic!("Error: {}", e),
 //     }
 // }
+
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use flate2::{write::GzEncoder, Compression};
+
+mod common;
+use common::bgzf_block;
+
+const METHYLOME: &str = "seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl
+1\t80\t+\tCG\t0\t8\t0.9999\tU\t0.0025
+1\t150\t+\tCG\t6\t8\t0.9999\tM\t0.7500
+1\t260\t+\tCG\t6\t8\t0.9999\tM\t0.7500
+";
+
+const ANNOTATION: &str = "1\t100\t200\tAT1G01010\t0\t+\n";
+
+/// Create a fresh fixture directory with an annotation, a methylome directory and an empty output directory
+fn fixture(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(name);
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("methylome")).unwrap();
+    fs::create_dir_all(root.join("windows")).unwrap();
+    fs::write(root.join("annotation.bed"), ANNOTATION).unwrap();
+    root
+}
+
+fn args(root: &Path) -> Args {
+    Args {
+        methylome: root.join("methylome").to_str().unwrap().to_string(),
+        genome: root.join("annotation.bed").to_str().unwrap().to_string(),
+        window_size: 50,
+        window_step: 50,
+        output_dir: root.join("windows").to_str().unwrap().to_string(),
+        absolute: true,
+        cutoff: 100,
+        invert: false,
+        ..Default::default()
+    }
+}
+
+#[test]
+#[serial]
+fn run_gzip_methylome() {
+    let root = fixture("extractor_run_gzip");
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(METHYLOME.as_bytes()).unwrap();
+    fs::write(root.join("methylome/G0.txt.gz"), encoder.finish().unwrap()).unwrap();
+
+    extract(args(&root)).unwrap();
+
+    let gene = fs::read_to_string(root.join("windows/CG/gene/50/G0.txt")).unwrap();
+    assert!(gene.contains("1\t150\t+\tCG"));
+    let upstream = fs::read_to_string(root.join("windows/CG/upstream/50/G0.txt")).unwrap();
+    assert!(upstream.contains("1\t80\t+\tCG"));
+    assert!(root.join("windows/CG/G0.txt_distribution.txt").exists());
+    assert!(!root.join("windows/CG/gene/50/G0.txt.gz").exists());
+}
+
+#[test]
+#[serial]
+fn run_bismark_coverage_methylome() {
+    let root = fixture("extractor_run_coverage");
+    fs::write(
+        root.join("methylome/G0.cov"),
+        "1\t80\t80\t0\t0\t8\n1\t150\t150\t75\t6\t2\n",
+    )
+    .unwrap();
+
+    extract(args(&root)).unwrap();
+
+    // The first line of a coverage file is a site, not a header, and sites are written in the methimpute layout
+    let upstream = fs::read_to_string(root.join("windows/CG/upstream/50/G0.cov")).unwrap();
+    assert!(upstream.contains("1\t80\t+\tCG\t0\t8\tNA\tNA\t0.0000"));
+    let gene = fs::read_to_string(root.join("windows/CG/gene/50/G0.cov")).unwrap();
+    assert!(gene.starts_with("seqnames\tstart\tstrand\tcontext"));
+    assert!(gene.contains("1\t150\t+\tCG\t6\t8\tNA\tNA\t0.7500"));
+}
+
+#[test]
+#[serial]
+fn run_bismark_coverage_with_track_line() {
+    let root = fixture("extractor_run_coverage_track");
+    let coverage = "1\t80\t80\t0\t0\t8\n1\t150\t150\t75\t6\t2\n";
+    fs::write(
+        root.join("methylome/G0.cov"),
+        format!("track type=bedGraph\n{coverage}"),
+    )
+    .unwrap();
+
+    // The track line is skipped until the format is detected from the first site, also when caching
+    extract(args(&root)).unwrap();
+    let gene = fs::read_to_string(root.join("windows/CG/gene/50/G0.cov")).unwrap();
+    assert!(gene.contains("1\t150\t+\tCG\t6\t8\tNA\tNA\t0.7500"));
+    index(IndexArgs {
+        methylome: root.join("methylome").to_str().unwrap().to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+
+    // A file without any line of a known format is still an error, which names the file
+    fs::remove_file(root.join("methylome/G0.cov.cache")).unwrap();
+    fs::write(root.join("methylome/G0.cov"), "track type=bedGraph\n").unwrap();
+    fs::remove_dir_all(root.join("windows")).unwrap();
+    let error = extract(args(&root)).unwrap_err().to_string();
+    assert!(error.contains("methylome file G0.cov,"));
+}
+
+#[test]
+#[serial]
+fn run_named_contigs_with_aliases() {
+    let root = fixture("extractor_run_contigs");
+    fs::write(
+        root.join("annotation.bed"),
+        "Chr1\t100\t200\tAT1G01010\t0\t+\nscaffold_300\t100\t200\tAT9G00010\t0\t+\n",
+    )
+    .unwrap();
+    fs::write(root.join("aliases.txt"), "Chr1\t1\n").unwrap();
+    fs::write(
+        root.join("methylome/G0.txt"),
+        "1\t150\t+\tCG\t6\t8\t0.9999\tM\t0.7500\nscaffold_300\t160\t+\tCG\t0\t8\t0.9999\tU\t0.0000\nChrM\t150\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n",
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.contig_aliases = Some(root.join("aliases.txt").to_str().unwrap().to_string());
+    extract(args).unwrap();
+
+    let gene = fs::read_to_string(root.join("windows/CG/gene/50/G0.txt")).unwrap();
+    assert!(gene.contains("1\t150\t+\tCG"));
+    assert!(gene.contains("scaffold_300\t160\t+\tCG"));
+    assert!(!gene.contains("ChrM"));
+}
+
+#[test]
+#[serial]
+fn run_all_contexts() {
+    let root = fixture("extractor_run_contexts");
+    fs::write(
+        root.join("methylome/G0.CX_report.txt"),
+        "1\t150\t+\t6\t2\tCG\tCGA\n1\t151\t+\t1\t7\tCHG\tCAG\n1\t152\t+\t0\t8\tCHH\tCAA\n",
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.context = vec![String::from("all")];
+    extract(args).unwrap();
+
+    for (context, position) in [("CG", 150), ("CHG", 151), ("CHH", 152)] {
+        let gene =
+            fs::read_to_string(root.join(format!("windows/{context}/gene/50/G0.CX_report.txt")))
+                .unwrap();
+        assert!(gene.contains(&format!("1\t{position}\t+\t{context}\t")));
+        assert_eq!(gene.lines().count(), 2);
+    }
+}
+
+#[test]
+#[serial]
+fn run_summary_table() {
+    let root = fixture("extractor_run_summary");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(root.join("methylome/G1.txt"), METHYLOME).unwrap();
+
+    extract(args(&root)).unwrap();
+
+    let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+    let mut lines = summary.lines();
+    assert_eq!(
+        lines.next(),
+        Some("sample\tcontext\tregion\twindow\tsites\tcoverage\tmean_level\tweighted_level\tfraction_methylated")
+    );
+    assert!(summary.contains("G0.txt\tCG\tgene\t50\t1\t8\t0.7500\t0.7500\t1.0000\n"));
+    assert!(summary.contains("G1.txt\tCG\tupstream\t50\t1\t8\t0.0025\t0.0000\t0.0000\n"));
+    assert!(summary.contains("G1.txt\tCG\tupstream\t0\t0\t0\tNA\tNA\tNA\n"));
+    // Rows are ordered by methylome file name
+    assert!(summary.find("G0.txt").unwrap() < summary.find("G1.txt").unwrap());
+}
+
+#[test]
+#[serial]
+fn run_profile_table() {
+    let root = fixture("extractor_run_profile");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+
+    extract(args(&root)).unwrap();
+
+    let profile = fs::read_to_string(root.join("windows/profile.tsv")).unwrap();
+    let lines: Vec<&str> = profile.lines().collect();
+    assert_eq!(
+        lines[0],
+        "sample\tcontext\tregion\twindow_index\twindow_start_label\tsites\tcoverage\tweighted_level\tmean_level"
+    );
+    assert_eq!(lines[1], "G0.txt\tCG\tupstream\t0\t-100bp\t0\t0\tNA\tNA");
+    assert_eq!(
+        lines[2],
+        "G0.txt\tCG\tupstream\t1\t-50bp\t1\t8\t0.0000\t0.0025"
+    );
+    assert_eq!(lines[3], "G0.txt\tCG\tgene\t2\t0bp\t1\t8\t0.7500\t0.7500");
+    assert_eq!(
+        lines[6],
+        "G0.txt\tCG\tdownstream\t5\t+50bp\t1\t8\t0.7500\t0.7500"
+    );
+}
+
+#[test]
+#[serial]
+fn run_with_pedigree() {
+    let root = fixture("extractor_run_pedigree");
+    fs::write(root.join("methylome/G0.txt.gz"), {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(METHYLOME.as_bytes()).unwrap();
+        encoder.finish().unwrap()
+    })
+    .unwrap();
+    fs::write(root.join("methylome/G1.txt"), METHYLOME).unwrap();
+    fs::write(
+        root.join("pedigree.tsv"),
+        "node\t0_0\t0\t0\tG0.txt.gz\nnode\t1_1\t1\t1\tG1.txt\nnode\t2_1\t2\t1\t-\nedge\t0_0\t1_1\nedge\t1_1\t2_1\n",
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.pedigree = Some(root.join("pedigree.tsv").to_str().unwrap().to_string());
+    extract(args.clone()).unwrap();
+
+    let window_dir = fs::canonicalize(root.join("windows/CG/gene/50")).unwrap();
+    let nodelist = fs::read_to_string(window_dir.join("nodelist.fn")).unwrap();
+    assert_eq!(
+        nodelist,
+        format!(
+            "filename,node,gen,meth\n{0}/G0.txt,0_0,0,Y\n{0}/G1.txt,1_1,1,Y\n-,2_1,2,N\n",
+            window_dir.to_str().unwrap()
+        )
+    );
+    let edgelist = fs::read_to_string(window_dir.join("edgelist.fn")).unwrap();
+    assert_eq!(edgelist, "from to\n0_0 1_1\n1_1 2_1\n");
+
+    // Methylomes of the pedigree need to be present
+    fs::remove_file(root.join("methylome/G1.txt")).unwrap();
+    assert!(extract(args).is_err());
+}
+
+#[test]
+#[serial]
+fn run_epimutation_rates() {
+    let root = fixture("extractor_run_epimutation");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(root.join("methylome/G1.txt"), METHYLOME).unwrap();
+    fs::write(
+        root.join("pedigree.tsv"),
+        "node\t0_0\t0\t0\tG0.txt\nnode\t1_1\t1\t1\tG1.txt\nedge\t0_0\t1_1\n",
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.epimutation = true;
+    assert!(extract(args.clone()).is_err());
+
+    args.pedigree = Some(root.join("pedigree.tsv").to_str().unwrap().to_string());
+    extract(args).unwrap();
+
+    let rates = fs::read_to_string(root.join("windows/epimutation_rates.tsv")).unwrap();
+    let mut lines = rates.lines();
+    assert_eq!(
+        lines.next().unwrap(),
+        "context\tregion\twindow\tpairs\tsites\talpha\tbeta\tweight\tintercept\trss"
+    );
+    // A single pair of samples is not enough to fit the model
+    assert!(lines.any(|l| l == "CG\tgene\t50\t1\t1\tNA\tNA\tNA\tNA\tNA"));
+}
+
+#[test]
+#[serial]
+fn run_overlapping_genes() {
+    let root = fixture("extractor_run_overlap");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    // The site at 150 is in the body of the first gene and upstream of the second, the site at 260 downstream of the first and in the body of the second
+    fs::write(
+        root.join("annotation.bed"),
+        format!("{ANNOTATION}1\t240\t300\tAT1G01020\t0\t+\n"),
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.force = true;
+    let sites = |args: Args, region: &str, window: usize| {
+        extract(args).unwrap();
+        let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+        let prefix = format!("G0.txt\tCG\t{region}\t{window}\t");
+        let row = summary.lines().find(|l| l.starts_with(&prefix)).unwrap();
+        row[prefix.len()..].split('\t').next().unwrap().to_owned()
+    };
+
+    args.overlap = OverlapPolicy::All;
+    assert_eq!(sites(args.clone(), "upstream", 0), "1");
+    assert_eq!(sites(args.clone(), "gene", 0), "2");
+    assert_eq!(sites(args.clone(), "downstream", 50), "1");
+
+    args.overlap = OverlapPolicy::PreferBody;
+    assert_eq!(sites(args.clone(), "upstream", 0), "0");
+    assert_eq!(sites(args.clone(), "gene", 0), "2");
+    assert_eq!(sites(args.clone(), "downstream", 50), "0");
+
+    args.overlap = OverlapPolicy::Discard;
+    assert_eq!(sites(args.clone(), "gene", 0), "0");
+    assert_eq!(sites(args, "upstream", 50), "1");
+}
+
+#[test]
+#[serial]
+fn run_truncated_flanks() {
+    let root = fixture("extractor_run_flanks");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(
+        root.join("annotation.bed"),
+        format!("{ANNOTATION}1\t240\t300\tAT1G01020\t0\t+\n"),
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.truncate_flanks = Some(Truncation::AnyStrand);
+    extract(args).unwrap();
+
+    let flanks = fs::read_to_string(root.join("windows/flanks.tsv")).unwrap();
+    assert_eq!(
+        flanks,
+        "gene\tcontig\tstart\tend\tstrand\tupstream\tdownstream
+AT1G01010\t1\t100\t200\t+\t100\t39
+AT1G01020\t1\t240\t300\t+\t39\t100
+"
+    );
+    // The site at 260 is no longer in the downstream flank of the first gene, nor the site at 150 upstream of the second
+    let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+    assert!(summary.contains("G0.txt\tCG\tdownstream\t50\t0\t"));
+    assert!(summary.contains("G0.txt\tCG\tupstream\t0\t0\t"));
+}
+
+#[test]
+#[serial]
+fn run_per_region_layout() {
+    let root = fixture("extractor_run_layout");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+
+    let mut args = args(&root);
+    args.upstream_window_size = Some(25);
+    args.gene_mode = Some(WindowMode::Relative);
+    args.downstream_length = Some(150);
+    args.downstream_window_size = Some(75);
+    args.window_step = 0;
+    extract(args).unwrap();
+
+    let windows = root.join("windows/CG");
+    assert!(windows.join("upstream/75").is_dir());
+    assert!(!windows.join("upstream/100").exists());
+    assert!(windows.join("downstream/75").is_dir());
+    assert!(!windows.join("downstream/150").exists());
+    assert!(!windows.join("gene/100").exists());
+
+    let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+    assert!(summary.contains("G0.txt\tCG\tupstream\t75\t1\t"));
+    assert!(summary.contains("G0.txt\tCG\tgene\t50\t1\t"));
+    assert!(summary.contains("G0.txt\tCG\tdownstream\t0\t1\t"));
+    let profile = fs::read_to_string(root.join("windows/profile.tsv")).unwrap();
+    assert!(profile.contains("\tupstream\t3\t-25bp\t1\t"));
+    assert!(profile.contains("\tgene\t5\t50%\t1\t"));
+}
+
+#[test]
+#[serial]
+fn run_metagene() {
+    let root = fixture("extractor_run_metagene");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+
+    let mut args = args(&root);
+    args.absolute = false;
+    args.metagene = true;
+    extract(args).unwrap();
+
+    // Flanks in 50 bp windows, the gene body in 5% windows, on one continuous axis
+    let distribution = fs::read_to_string(root.join("windows/CG/G0.txt_distribution.txt")).unwrap();
+    let combined = distribution.split("Combined\n").nth(1).unwrap();
+    assert_eq!(combined.lines().count(), 24);
+    assert!(combined.starts_with("0,-100bp,0\n1,-50bp,1\n2,0%,0\n"));
+    assert!(combined.contains("\n11,45%,1\n12,50%,1\n"));
+    assert!(combined.ends_with("22,+0bp,0\n23,+50bp,1\n"));
+}
+
+#[test]
+#[serial]
+fn run_tss_anchored() {
+    let root = fixture("extractor_run_anchor");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+
+    let mut args = args(&root);
+    args.anchor = Some(Anchor::Tss);
+    extract(args).unwrap();
+
+    let windows = root.join("windows/CG");
+    for window in ["-100", "-50", "0", "50"] {
+        assert!(windows.join("tss").join(window).is_dir());
+    }
+    assert!(!windows.join("upstream").exists());
+    assert!(!windows.join("gene").exists());
+
+    let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+    assert!(summary.contains("G0.txt\tCG\ttss\t-100\t0\t"));
+    assert!(summary.contains("G0.txt\tCG\ttss\t-50\t1\t"));
+    assert!(summary.contains("G0.txt\tCG\ttss\t0\t1\t"));
+    assert!(summary.contains("G0.txt\tCG\ttss\t50\t1\t"));
+}
+
+#[test]
+#[serial]
+fn run_transcripts() {
+    let root = fixture("extractor_run_transcripts");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    // Exons from 100 to 130 and 140 to 200, coding from 110 to 180
+    fs::write(
+        root.join("annotation.bed"),
+        "1\t100\t200\tAT1G01010\t0\t+\t110\t180\t0\t2\t30,60\t0,40\n",
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.transcripts = true;
+    extract(args).unwrap();
+
+    let features = fs::read_to_string(root.join("windows/features.tsv")).unwrap();
+    assert!(features.starts_with("sample\tcontext\tclass\t"));
+    assert!(features.contains("G0.txt\tCG\tCDS\t1\t8\t"));
+    assert!(features.contains("G0.txt\tCG\tintron\t0\t0\t"));
+    assert_eq!(features.lines().count(), 6);
+
+    // The site at 150 is 40 bp into the spliced transcript
+    assert!(root.join("windows/CG/spliced/50").is_dir());
+    let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+    assert!(summary.contains("G0.txt\tCG\tspliced\t0\t1\t"));
+    assert!(summary.contains("G0.txt\tCG\tspliced\t50\t0\t"));
+}
+
+#[test]
+#[serial]
+fn run_feature_sets() {
+    let root = fixture("extractor_run_feature_sets");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(root.join("te.bed"), "1\t240\t300\tTE1\t0\t+\n").unwrap();
+
+    let mut args = args(&root);
+    let te = format!("te={}", root.join("te.bed").to_str().unwrap());
+    args.features = vec![te.parse().unwrap()];
+    extract(args).unwrap();
+
+    // One window tree and summary per feature set, the annotation of --genome labelled genes
+    let windows = root.join("windows");
+    assert!(windows.join("genes/CG/gene/50").is_dir());
+    assert!(windows.join("te/CG/gene/50").is_dir());
+    assert!(!windows.join("CG").exists());
+
+    let genes = fs::read_to_string(windows.join("genes/summary.tsv")).unwrap();
+    assert!(genes.contains("G0.txt\tCG\tgene\t50\t1\t"));
+    assert!(genes.contains("G0.txt\tCG\tdownstream\t50\t1\t"));
+    let te = fs::read_to_string(windows.join("te/summary.tsv")).unwrap();
+    assert!(te.contains("G0.txt\tCG\tupstream\t0\t1\t"));
+    assert!(te.contains("G0.txt\tCG\tgene\t0\t1\t"));
+    assert!(te.contains("G0.txt\tCG\tgene\t50\t0\t"));
+}
+
+#[test]
+#[serial]
+fn run_genome_wide_tiles() {
+    let root = fixture("extractor_run_tiles");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(
+        root.join("genome.fa.fai"),
+        "1\t300\t3\t60\t61\nChrC\t150\t400\t60\t61\n",
+    )
+    .unwrap();
+
+    // No annotation needed
+    let mut args = args(&root);
+    args.genome = String::new();
+    args.tiles = Some(root.join("genome.fa.fai").to_str().unwrap().to_string());
+    args.tile_size = 100;
+    extract(args).unwrap();
+
+    let tiles = fs::read_to_string(root.join("windows/tiles.tsv")).unwrap();
+    assert_eq!(
+        tiles.lines().collect::<Vec<_>>()[..4],
+        [
+            "sample\tcontext\tcontig\tstart\tend\tsites\tcoverage\tmean_level\tweighted_level\tfraction_methylated",
+            "G0.txt\tCG\t1\t0\t100\t1\t8\t0.0025\t0.0000\t0.0000",
+            "G0.txt\tCG\t1\t100\t200\t1\t8\t0.7500\t0.7500\t1.0000",
+            "G0.txt\tCG\t1\t200\t300\t1\t8\t0.7500\t0.7500\t1.0000",
+        ]
+    );
+    assert!(tiles.contains("G0.txt\tCG\tChrC\t100\t150\t0\t0\tNA\tNA\tNA\n"));
+    assert!(!root.join("windows/summary.tsv").exists());
+}
+
+#[test]
+#[serial]
+fn run_gene_matrix() {
+    let root = fixture("extractor_run_matrix");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(
+        root.join("annotation.bed"),
+        format!("{ANNOTATION}1\t240\t300\tAT1G01020\t0\t+\n"),
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.matrix = true;
+    extract(args).unwrap();
+
+    let matrix = fs::read_to_string(root.join("windows/CG/G0.txt_matrix.tsv")).unwrap();
+    assert_eq!(
+        matrix,
+        "gene\tcontig\tstart\tend\tstrand\twindow_index\twindow_start_label\tsites\tcoverage\tweighted_level
+AT1G01010\t1\t100\t200\t+\t1\t-50bp\t1\t8\t0.0000
+AT1G01010\t1\t100\t200\t+\t2\t0bp\t1\t8\t0.7500
+AT1G01010\t1\t100\t200\t+\t3\t50bp\t1\t8\t0.7500
+AT1G01010\t1\t100\t200\t+\t5\t+50bp\t1\t8\t0.7500
+AT1G01020\t1\t240\t300\t+\t0\t-100bp\t1\t8\t0.7500
+AT1G01020\t1\t240\t300\t+\t2\t0bp\t1\t8\t0.7500
+"
+    );
+}
+
+#[test]
+#[serial]
+fn run_gene_summary() {
+    let root = fixture("extractor_run_gene_summary");
+    fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+    fs::write(
+        root.join("annotation.bed"),
+        format!("{ANNOTATION}1\t1000\t1100\tAT1G01030\t0\t+\n"),
+    )
+    .unwrap();
+
+    let mut args = args(&root);
+    args.gene_summary = true;
+    extract(args).unwrap();
+
+    // Genes without sites are listed as well
+    let genes = fs::read_to_string(root.join("windows/genes.tsv")).unwrap();
+    assert_eq!(
+        genes,
+        "sample\tcontext\tgene\tcontig\tstart\tend\tstrand\tsites\tcovered_sites\tupstream_weighted_level\tbody_weighted_level\tdownstream_weighted_level\tfraction_methylated
+G0.txt\tCG\tAT1G01010\t1\t100\t200\t+\t1\t1\t0.0000\t0.7500\t0.7500\t1.0000
+G0.txt\tCG\tAT1G01030\t1\t1000\t1100\t+\t0\t0\tNA\tNA\tNA\tNA
+"
+    );
+}
+
+#[test]
+#[serial]
+fn run_bounded_buffer() {
+    // Window files written in many small chunks are the same as those written at once, also with inverted windows
+    let files = [
+        "CG/upstream/0/G0.txt",
+        "CG/upstream/50/G0.txt",
+        "CG/gene/50/G0.txt",
+        "CG/downstream/50/G0.txt",
+        "summary.tsv",
+    ];
+    let mut outputs = Vec::new();
+    for buffer_size in [0, 1] {
+        let root = fixture("extractor_run_bounded_buffer");
+        fs::write(root.join("methylome/G0.txt"), METHYLOME).unwrap();
+        let mut args = args(&root);
+        args.buffer_size = buffer_size;
+        args.invert = true;
+        extract(args).unwrap();
+        let output: Vec<String> = files
+            .iter()
+            .map(|file| fs::read_to_string(root.join("windows").join(file)).unwrap())
+            .collect();
+        outputs.push(output);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert!(outputs[1][3].ends_with("1\t260\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n"));
+}
+
+#[test]
+#[serial]
+fn run_unsorted_methylome() {
+    // Unsorted sites are looked up in the index instead of the sweep, with the same result
+    let root = fixture("extractor_run_unsorted_methylome");
+    let mut lines: Vec<&str> = METHYLOME.lines().collect();
+    lines[1..].reverse();
+    fs::write(root.join("methylome/G0.txt"), lines.join("\n") + "\n").unwrap();
+    fs::write(root.join("methylome/G1.txt"), METHYLOME).unwrap();
+    let mut args = args(&root);
+    args.gene_summary = true;
+    extract(args).unwrap();
+
+    let genes = fs::read_to_string(root.join("windows/genes.tsv")).unwrap();
+    let rows: Vec<&str> = genes.lines().skip(1).collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].replace("G0.txt", "G1.txt"), rows[1]);
+    let summary = fs::read_to_string(root.join("windows/summary.tsv")).unwrap();
+    assert_eq!(summary.matches("\t1\t8\t0.7500").count(), 6);
+}
+
+#[test]
+#[serial]
+fn run_chunked_methylome() {
+    // A methylome large enough to be split into chunks gives the same results as read by a single thread
+    let mut methylome =
+        String::from("seqnames\tstart\tstrand\tcontext\tcounts.methylated\tcounts.total\tposteriorMax\tstatus\trc.meth.lvl\n");
+    for location in 1..130_000 {
+        let methylated = location % 9;
+        methylome += &format!(
+            "1\t{location}\t+\tCG\t{methylated}\t8\t0.9999\tM\t{:.4}\n",
+            methylated as f32 / 8.0
+        );
+    }
+    assert!(methylome.len() > 4 << 20);
+    let files = [
+        "CG/upstream/0/G0.txt",
+        "CG/gene/50/G0.txt",
+        "CG/downstream/50/G0.txt",
+        "summary.tsv",
+        "genes.tsv",
+        "tiles.tsv",
+    ];
+    let mut outputs = Vec::new();
+    for threads in [1, 4] {
+        let root = fixture("extractor_run_chunked_methylome");
+        fs::write(root.join("methylome/G0.txt"), &methylome).unwrap();
+        fs::write(root.join("genome.fai"), "1\t130000\n").unwrap();
+        fs::write(
+            root.join("annotation.bed"),
+            format!("{ANNOTATION}1\t30000\t30100\tAT1G01030\t0\t+\n"),
+        )
+        .unwrap();
+        let mut args = args(&root);
+        args.threads = threads;
+        args.buffer_size = 1000;
+        args.gene_summary = true;
+        args.tiles = Some(root.join("genome.fai").to_str().unwrap().to_string());
+        args.tile_size = 10_000;
+        extract(args).unwrap();
+        let output: Vec<String> = files
+            .iter()
+            .map(|file| fs::read_to_string(root.join("windows").join(file)).unwrap())
+            .collect();
+        // No parts of chunks are left behind
+        assert!(!root.join("windows/CG/gene/50/G0.txt.part1").exists());
+        outputs.push(output);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert_eq!(outputs[0][1].lines().count(), 103);
+}
+
+#[test]
+#[serial]
+fn run_indexed_methylome() {
+    // The methylome is bgzipped in two blocks, the second one with a site far from the gene
+    let far = "1\t500000\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n";
+    let first = bgzf_block(METHYLOME.as_bytes());
+    let second = bgzf_block(far.as_bytes());
+    let header = METHYLOME.find('\n').unwrap() as u64 + 1;
+    let second_offset = (first.len() as u64) << 16;
+    let content = [first, second, bgzf_block(b"")].concat();
+
+    // Tabix index of contig 1, with the sites near the gene in the first bin of 16 kb and the far site in the 31st
+    let mut index = b"TBI\x01".to_vec();
+    for field in [1, 0, 1, 2, 0, b'#' as i32, 1, 2] {
+        index.extend(field.to_le_bytes());
+    }
+    index.extend(b"1\0");
+    index.extend(2i32.to_le_bytes());
+    for (bin, start, end) in [
+        (4681u32, header, METHYLOME.len() as u64),
+        (4681 + 30, second_offset, second_offset | far.len() as u64),
+    ] {
+        index.extend(bin.to_le_bytes());
+        index.extend(1i32.to_le_bytes());
+        index.extend(start.to_le_bytes());
+        index.extend(end.to_le_bytes());
+    }
+    index.extend(0i32.to_le_bytes());
+
+    let files = [
+        "CG/upstream/0/G0.txt",
+        "CG/gene/50/G0.txt",
+        "CG/downstream/50/G0.txt",
+        "summary.tsv",
+        "profile.tsv",
+    ];
+    let mut outputs = Vec::new();
+    for indexed in [false, true] {
+        let root = fixture("extractor_run_indexed_methylome");
+        fs::write(root.join("methylome/G0.txt.gz"), &content).unwrap();
+        if indexed {
+            fs::write(root.join("methylome/G0.txt.gz.tbi"), bgzf_block(&index)).unwrap();
+        }
+        let mut args = args(&root);
+        args.methylome = root.join("methylome").to_str().unwrap().to_string();
+        extract(args).unwrap();
+        let output: Vec<String> = files
+            .iter()
+            .map(|file| fs::read_to_string(root.join("windows").join(file)).unwrap())
+            .collect();
+        outputs.push(output);
+    }
+    assert_eq!(outputs[0], outputs[1]);
+    assert!(outputs[1][1].ends_with("1\t150\t+\tCG\t6\t8\t0.9999\tM\t0.7500\n"));
+}
+
+#[test]
+#[serial]
+fn run_cached_methylome() {
+    let files = [
+        "CG/upstream/50/G0.txt",
+        "CG/gene/50/G0.txt",
+        "CG/downstream/50/G0.txt",
+        "summary.tsv",
+        "genes.tsv",
+    ];
+    let root = fixture("extractor_run_cached_methylome");
+    // A site with all digits of its level, whose strand is inverted with the gene by --invert
+    let line = "1\t170\t+\tCG\t1\t3\t0.999999881\tM\t0.333333333";
+    fs::write(
+        root.join("methylome/G0.txt"),
+        format!("{METHYLOME}{line}\n"),
+    )
+    .unwrap();
+    let run = |invert: bool| {
+        fs::remove_dir_all(root.join("windows")).unwrap();
+        let mut args = args(&root);
+        args.gene_summary = true;
+        args.invert = invert;
+        extract(args).unwrap();
+        files
+            .iter()
+            .map(|file| fs::read_to_string(root.join("windows").join(file)).unwrap())
+            .collect::<Vec<String>>()
+    };
+    let text = run(false);
+    let inverted = run(true);
+
+    index(IndexArgs {
+        methylome: root.join("methylome").to_str().unwrap().to_string(),
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(root.join("methylome/G0.txt.cache").exists());
+    // The cache is not taken for a methylome, and the sites read from it are the same as those parsed from the text
+    assert_eq!(run(false), text);
+    assert!(!root.join("windows/CG/gene/50/G0.txt.cache").exists());
+    // The lines are written as they are in the methylome, even with the strand inverted
+    assert_eq!(run(true), inverted);
+    assert!(inverted[1].contains(line));
+
+    // A stale cache is ignored
+    fs::write(
+        root.join("methylome/G0.txt"),
+        METHYLOME.replace("1\t260\t", "1\t160\t"),
+    )
+    .unwrap();
+    assert_ne!(run(false), text);
+}